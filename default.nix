@@ -8,7 +8,8 @@ let
   };
 
   rust = nur.repos.mozilla.rustChannelOf {
-    date = "2019-11-07"; channel = "stable";
+    # 1.85.0, needed by rusqlite and its dependencies
+    date = "2025-02-20"; channel = "stable";
   };
 
   buildRustPackage' = rustPlatform.buildRustPackage.override {
@@ -24,9 +25,10 @@ in buildRustPackage' rec {
   ];
   src = filterSource (path: type: !elem path ignore) ./.;
 
-  nativeBuildInputs = [ pkgconfig ];
+  nativeBuildInputs = [ pkg-config ];
   buildInputs = [ openssl ];
 
   RUST_BACKTRACE = "1";
-  cargoSha256 = "05kj65s1a2kygrw4jz2cg1jw00yxhgj8mdrki9x2i331v8h4kxg8";
+  # Vendored from the lock file, so there is no hash to update when dependencies change
+  cargoLock.lockFile = ./Cargo.lock;
 }
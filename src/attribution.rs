@@ -0,0 +1,142 @@
+use std::collections::{ BTreeMap, HashMap };
+
+use serde_derive::Serialize;
+
+use crate::{ StoreHash, StoreCache, Closure, binary_cache::BinaryCache };
+
+/// A cache having a narinfo for some path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheHit {
+    /// Index into the configured caches
+    pub cache: usize,
+    pub file_size: Option<u64>,
+    pub nar_size: u64,
+    pub trusted: bool
+}
+
+/// Which caches have which paths, when asking every cache instead of stopping at the first
+#[derive(Default)]
+pub struct CacheMatrix(HashMap<StoreHash, Vec<CacheHit>>);
+impl CacheMatrix {
+    pub fn record(&mut self, hash: StoreHash, hits: Vec<CacheHit>) {
+        if !hits.is_empty() { self.0.insert(hash, hits); }
+    }
+
+    pub fn hits(&self, hash: &StoreHash) -> &[CacheHit] {
+        self.0.get(hash).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn attribution(&self, closure: &Closure, store: &StoreCache, caches: &[Box<dyn BinaryCache>])
+            -> Attribution {
+        let mut coverage: Vec<CacheCoverage> = caches.iter()
+            .map(|cache| CacheCoverage { url: cache.url().to_string(), ..CacheCoverage::default() })
+            .collect();
+        let mut sources = BTreeMap::new();
+
+        for hash in closure.entries() {
+            let hits = self.hits(hash);
+            let path = store.path_of(*hash);
+
+            for hit in hits {
+                let cache = &mut coverage[hit.cache];
+                if hit.trusted {
+                    cache.found += 1;
+                    cache.file_size += hit.file_size.unwrap_or(0);
+                    cache.nar_size += hit.nar_size;
+                } else {
+                    cache.untrusted += 1;
+                }
+            }
+
+            let trusted: Vec<&CacheHit> = hits.iter().filter(|hit| hit.trusted).collect();
+            // Nix substitutes from the first cache with a trusted narinfo, if it substitutes at all
+            if let (Some(first), true) = (trusted.first(), store.is_substitutable(*hash)) {
+                let cache = &mut coverage[first.cache];
+                cache.substituted += 1;
+                cache.download_size += first.file_size.unwrap_or(0);
+                sources.insert(path.clone(), cache.url.clone());
+            }
+
+            if let [only] = trusted[..] {
+                coverage[only.cache].exclusive.push(StoreHash::split_path(&path)
+                    .map(|(_, name)| name)
+                    .unwrap_or(path));
+            }
+        }
+
+        for cache in &mut coverage { cache.exclusive.sort() }
+        Attribution { caches: coverage, sources }
+    }
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct CacheCoverage {
+    pub url: String,
+    /// Paths this cache has a trusted narinfo for
+    pub found: u64,
+    pub file_size: u64,
+    pub nar_size: u64,
+    /// Paths this cache has, but not signed by a trusted key
+    pub untrusted: u64,
+    /// Paths that would actually be fetched from this cache
+    pub substituted: u64,
+    /// Compressed size of the paths that would be fetched from this cache
+    pub download_size: u64,
+    /// Names of paths no other cache provides
+    pub exclusive: Vec<String>
+}
+
+#[derive(Debug, Serialize)]
+pub struct Attribution {
+    pub caches: Vec<CacheCoverage>,
+    /// Which cache each substitutable path would be fetched from, by store path
+    pub sources: BTreeMap<String, String>
+}
+
+#[test]
+fn attribute_to_caches() {
+    use crate::{ StoreItem, derivation::test_drv, narinfo::{ NarInfo, test_cache, test_narinfo, test_store } };
+
+    let narinfo = |name: &str, file_size: u64| NarInfo { file_size: Some(file_size), ..test_narinfo(name, &[]) };
+
+    let shared = "11111111111111111111111111111111-shared";
+    let public = "22222222222222222222222222222222-public";
+    let private = "33333333333333333333333333333333-private";
+    // cached, but Nix won't fetch it
+    let local = "44444444444444444444444444444444-local";
+    let first = test_cache("public");
+    first.insert(narinfo(shared, 10)).unwrap();
+    first.insert(narinfo(public, 20)).unwrap();
+    let second = test_cache("private");
+    second.insert(narinfo(shared, 40)).unwrap();
+    second.insert(narinfo(private, 80)).unwrap();
+    second.insert(narinfo(local, 160)).unwrap();
+
+    let mut local_drv = test_drv(local, &[]);
+    local_drv.env.push((String::from("allowSubstitutes"), String::new()));
+    let mut store = test_store(&[("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-local.drv", local_drv)]);
+    let deriver = StoreHash::from_name("00000000000000000000000000000000").unwrap();
+    let mut roots = vec![StoreHash::from_name(local).unwrap()];
+    for name in &[shared, public, private] {
+        let (hash, name) = StoreHash::split(name).unwrap();
+        store.items.insert(hash, StoreItem::Output(name.to_owned(), deriver));
+        roots.push(hash);
+    }
+
+    let caches: [Box<dyn BinaryCache>; 2] = [Box::new(first), Box::new(second)];
+    let mut matrix = CacheMatrix::default();
+    futures::executor::block_on(store.fetch_narinfo(&roots, &caches, 4, None, None, Some(&mut matrix)));
+
+    let mut closure = Closure::empty();
+    for root in &roots { closure.add_runtime_closure_of(*root, &store) }
+    let attribution = matrix.attribution(&closure, &store, &caches);
+
+    let (public, private) = (&attribution.caches[0], &attribution.caches[1]);
+    assert_eq!((public.found, public.file_size, public.substituted, public.download_size), (2, 30, 2, 30));
+    assert_eq!((private.found, private.file_size, private.substituted, private.download_size), (3, 280, 1, 80));
+    assert_eq!(public.exclusive, vec!["public"]);
+    assert_eq!(private.exclusive, vec!["local", "private"]);
+    assert_eq!(attribution.sources.len(), 3);
+    assert_eq!(attribution.sources["/nix/store/11111111111111111111111111111111-shared"], "memory://public/");
+    assert_eq!(attribution.sources["/nix/store/33333333333333333333333333333333-private"], "memory://private/");
+}
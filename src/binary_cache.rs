@@ -0,0 +1,217 @@
+use std::{
+    fs, io,
+    collections::HashMap,
+    num::NonZeroU32,
+    path::PathBuf,
+    sync::{ Mutex, atomic::{ AtomicUsize, Ordering } },
+    time::Duration
+};
+
+use futures::future::{ self, BoxFuture, FutureExt };
+use tokio::timer::delay_for;
+use reqwest::{ Client, StatusCode };
+use url::Url;
+use log::trace;
+
+use crate::{ Error, StoreHash, narinfo::NarInfo, realisation::Realisation };
+
+/// Somewhere .narinfo files can be looked up, found / not found / error
+pub trait BinaryCache: Send + Sync {
+    /// Identifies this cache, e.g. as key of the narinfo cache
+    fn url(&self) -> &Url;
+
+    /// Local caches are as cheap to query as the narinfo cache, so their lookups aren't persisted
+    fn is_local(&self) -> bool { false }
+
+    fn narinfo(&self, hash: StoreHash) -> BoxFuture<'_, Result<Option<NarInfo>, Error>>;
+
+    /// Where a floating output ended up, by id like sha256:<hex>!out, if the cache knows
+    fn realisation(&self, _id: &str) -> BoxFuture<'_, Result<Option<Realisation>, Error>> {
+        future::ready(Ok(None)).boxed()
+    }
+}
+
+/// Picks the implementation by URL scheme, e.g. file:///srv/cache or https://cache.nixos.org
+pub fn from_url(url: Url, max_attempts: NonZeroU32) -> Result<Box<dyn BinaryCache>, Error> {
+    Ok(match url.scheme() {
+        "file" => Box::new(FileBinaryCache::new(url)?),
+        "http" | "https" => Box::new(HttpBinaryCache::new(Client::new(), url, max_attempts)),
+        _ => return Err(Error::InvalidCache(url.to_string()))
+    })
+}
+
+// Url::join replaces the last segment, unless it ends in a slash
+fn as_directory(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+pub struct HttpBinaryCache {
+    client: Client,
+    root: Url,
+    max_attempts: NonZeroU32
+}
+
+impl HttpBinaryCache {
+    pub fn new(client: Client, root: Url, max_attempts: NonZeroU32) -> Self {
+        HttpBinaryCache { client, root: as_directory(root), max_attempts }
+    }
+
+    async fn fetch<T>(&self, url: &Url, parse: fn(&[u8]) -> Result<T, Error>) -> Result<Option<T>, Error> {
+        let fetch_error = |e| Error::Fetch(url.to_string(), e);
+        let response = self.client.get(url.clone()).send().await.map_err(fetch_error)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None)
+        }
+
+        let bytes = response.error_for_status()
+            .map_err(fetch_error)?
+            .bytes().await
+            .map_err(fetch_error)?;
+        parse(&bytes[..]).map(Some)
+    }
+
+    async fn fetch_with_retries<T>(&self, path: &str, parse: fn(&[u8]) -> Result<T, Error>)
+            -> Result<Option<T>, Error> {
+        let url = self.root.join(path).expect("Invalid URL join");
+        trace!("fetching {}", url);
+        let (mut attempt, mut delay) = (1, 64);
+
+        // at least one attempt, so a failure is never mistaken for absence
+        loop {
+            match self.fetch(&url, parse).await {
+                Err(Error::Fetch(_, _)) if attempt < self.max_attempts.get() => {
+                    delay_for(Duration::from_millis(delay)).await;
+                    attempt += 1;
+                    delay *= 2;
+                }
+                response => return response
+            }
+        }
+    }
+}
+
+impl BinaryCache for HttpBinaryCache {
+    fn url(&self) -> &Url { &self.root }
+
+    fn narinfo(&self, hash: StoreHash) -> BoxFuture<'_, Result<Option<NarInfo>, Error>> {
+        let path = format!("{}.narinfo", hash.to_str());
+        async move { self.fetch_with_retries(&path, NarInfo::parse).await }.boxed()
+    }
+
+    fn realisation(&self, id: &str) -> BoxFuture<'_, Result<Option<Realisation>, Error>> {
+        let path = format!("realisations/{}.doi", id);
+        async move { self.fetch_with_retries(&path, Realisation::parse).await }.boxed()
+    }
+}
+
+/// e.g. populated by nix copy --to file:///srv/cache
+pub struct FileBinaryCache {
+    root: Url,
+    dir: PathBuf
+}
+
+impl FileBinaryCache {
+    pub fn new(root: Url) -> Result<Self, Error> {
+        let dir = root.to_file_path().map_err(|()| Error::InvalidCache(root.to_string()))?;
+        Ok(FileBinaryCache { root, dir })
+    }
+
+    fn read<T>(&self, path: PathBuf, parse: fn(&[u8]) -> Result<T, Error>) -> Result<Option<T>, Error> {
+        trace!("reading {}", path.display());
+        match fs::read(&path) {
+            Ok(bytes) => parse(&bytes).map(Some),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::ReadCache(path, e))
+        }
+    }
+}
+
+impl BinaryCache for FileBinaryCache {
+    fn url(&self) -> &Url { &self.root }
+    fn is_local(&self) -> bool { true }
+
+    fn narinfo(&self, hash: StoreHash) -> BoxFuture<'_, Result<Option<NarInfo>, Error>> {
+        let path = self.dir.join(format!("{}.narinfo", hash.to_str()));
+        future::ready(self.read(path, NarInfo::parse)).boxed()
+    }
+
+    fn realisation(&self, id: &str) -> BoxFuture<'_, Result<Option<Realisation>, Error>> {
+        let path = self.dir.join("realisations").join(format!("{}.doi", id));
+        future::ready(self.read(path, Realisation::parse)).boxed()
+    }
+}
+
+/// Serves narinfos from memory, e.g. to test coverage logic without a network
+pub struct MemoryBinaryCache {
+    url: Url,
+    narinfos: Mutex<HashMap<StoreHash, NarInfo>>,
+    realisations: Mutex<HashMap<String, Realisation>>,
+    requests: AtomicUsize
+}
+
+impl MemoryBinaryCache {
+    pub fn new(url: Url) -> Self {
+        MemoryBinaryCache {
+            url,
+            narinfos: Mutex::default(),
+            realisations: Mutex::default(),
+            requests: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn insert(&self, narinfo: NarInfo) -> Result<(), Error> {
+        let hash = StoreHash::from_path(&narinfo.store_path)?;
+        self.narinfos.lock().expect("Poisoned lock").insert(hash, narinfo);
+        Ok(())
+    }
+
+    pub fn insert_realisation(&self, realisation: Realisation) {
+        self.realisations.lock().expect("Poisoned lock").insert(realisation.id.clone(), realisation);
+    }
+
+    /// How often narinfo was called so far
+    pub fn requests(&self) -> usize { self.requests.load(Ordering::SeqCst) }
+}
+
+impl BinaryCache for MemoryBinaryCache {
+    fn url(&self) -> &Url { &self.url }
+    fn is_local(&self) -> bool { true }
+
+    fn narinfo(&self, hash: StoreHash) -> BoxFuture<'_, Result<Option<NarInfo>, Error>> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let narinfo = self.narinfos.lock().expect("Poisoned lock").get(&hash).cloned();
+        future::ready(Ok(narinfo)).boxed()
+    }
+
+    fn realisation(&self, id: &str) -> BoxFuture<'_, Result<Option<Realisation>, Error>> {
+        let realisation = self.realisations.lock().expect("Poisoned lock").get(id).cloned();
+        future::ready(Ok(realisation)).boxed()
+    }
+}
+
+#[test]
+fn cache_directory_url() {
+    let cache = HttpBinaryCache::new(Client::new(), Url::parse("https://example.org/cache").unwrap(), NonZeroU32::new(1).unwrap());
+    assert_eq!(cache.url().as_str(), "https://example.org/cache/");
+
+    let cache = HttpBinaryCache::new(Client::new(), Url::parse("https://cache.nixos.org").unwrap(), NonZeroU32::new(1).unwrap());
+    assert_eq!(cache.url().as_str(), "https://cache.nixos.org/");
+}
+
+#[test]
+fn memory_cache() {
+    let cache = crate::narinfo::test_cache("test");
+    let narinfo = NarInfo::parse(include_bytes!("../assets/dejagnu.narinfo")).unwrap();
+    let hash = StoreHash::from_path(&narinfo.store_path).unwrap();
+    cache.insert(narinfo.clone()).unwrap();
+
+    let found = futures::executor::block_on(cache.narinfo(hash)).unwrap();
+    assert_eq!(found, Some(narinfo));
+    let missing = futures::executor::block_on(cache.narinfo(StoreHash::from_name("00000000000000000000000000000000").unwrap()));
+    assert!(matches!(missing, Ok(None)));
+    assert_eq!(cache.requests(), 2);
+}
@@ -1,4 +1,4 @@
-use std::{ fs, path::Path };
+use std::{ fs, borrow::Cow, path::Path, collections::{ BTreeMap, BTreeSet, HashMap } };
 
 use nom::{
     IResult,
@@ -17,9 +17,9 @@ use nom::{
     },
     character::complete::char
 };
-use log::trace;
+use log::{ trace, warn };
 
-use crate::{ StoreHash, StoreItem, StoreCache };
+use crate::{ Error, StoreHash, StoreItem, StoreCache, hash::* };
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Drv {
@@ -33,25 +33,242 @@ pub struct Drv {
 }
 
 impl Drv {
-    pub fn read_from<P: AsRef<Path>>(path: P) -> Self {
-        trace!("reading derivation {}", path.as_ref().display());
-        let file_content = fs::read(path).expect("Unable to read derivation");
-        let (rest, drv) = drv(&file_content).expect("Unable to parse derivation");
-        assert!(rest.is_empty(), "Less than the entire drv was parsed");
-        drv
+    pub fn read_from<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        trace!("reading derivation {}", path.display());
+        let file_content = fs::read(path).map_err(|e| Error::ReadDrv(path.to_owned(), e))?;
+        Drv::parse(path, &file_content)
+    }
+
+    /// Parses the contents of a .drv file, path is only used for errors
+    pub fn parse<P: AsRef<Path>>(path: P, bytes: &[u8]) -> Result<Self, Error> {
+        match drv(bytes) {
+            // Less than the entire drv was parsed
+            Ok((b"", drv)) => Ok(drv),
+            _ => Err(Error::ParseDrv(path.as_ref().to_owned()))
+        }
     }
 
     pub fn find_name(&self) -> String {
-        self.env.iter()
-            .find(|(k, _)| k == "name")
-            .map(|(_, v)| v.clone())
-            .unwrap_or_else(|| String::from("unknown"))
+        self.attrs().name()
+    }
+
+    /// Attributes passed to the builder, decoding __json for __structuredAttrs on every call.
+    /// StoreCache::drv_attrs only decodes it once for registered derivations
+    pub fn attrs(&self) -> Attrs<'_> {
+        match self.structured_attrs() {
+            Some(attrs) => Attrs::Structured(Cow::Owned(attrs)),
+            None => Attrs::Env(&self.env)
+        }
+    }
+
+    /// Decodes __json, if there is one and it's valid
+    pub(crate) fn structured_attrs(&self) -> Option<serde_json::Map<String, serde_json::Value>> {
+        let (_, json) = self.env.iter().find(|(k, _)| k == "__json")?;
+        serde_json::from_str(json)
+            .map_err(|e| warn!("ignoring invalid __json of derivation: {}", e))
+            .ok()
     }
 
     pub fn find_output(&self, key: &str) -> Option<&DrvOutput> {
         self.outputs.iter()
             .find(|output| output.key == key)
     }
+
+    /// Serializes like Nix does, in canonical order, so parsing and writing
+    /// a .drv from the store gives back exactly the same bytes
+    pub fn to_aterm(&self) -> String {
+        let mut outputs: Vec<&DrvOutput> = self.outputs.iter().collect();
+        outputs.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut input_drvs: Vec<&InputDrv> = self.input_drvs.iter().collect();
+        input_drvs.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut input_srcs: Vec<&String> = self.input_srcs.iter().collect();
+        input_srcs.sort();
+        let mut env: Vec<&(String, String)> = self.env.iter().collect();
+        env.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut out = String::from("Derive(");
+        write_list(&mut out, outputs, |out, DrvOutput { key, path, hash_algo, hash }| {
+            out.push('(');
+            write_strings(out, &[key, path, hash_algo, hash]);
+            out.push(')');
+        });
+        out.push(',');
+        write_list(&mut out, input_drvs, |out, InputDrv { path, outputs }| {
+            let mut outputs: Vec<&String> = outputs.iter().collect();
+            outputs.sort();
+            out.push('(');
+            write_string(out, path);
+            out.push(',');
+            write_list(out, outputs, |out, output| write_string(out, output));
+            out.push(')');
+        });
+        out.push(',');
+        write_list(&mut out, input_srcs, |out, src| write_string(out, src));
+        out.push(',');
+        write_strings(&mut out, &[&self.platform, &self.builder]);
+        out.push(',');
+        write_list(&mut out, &self.builder_args, |out, arg| write_string(out, arg));
+        out.push(',');
+        write_list(&mut out, env, |out, (key, value)| {
+            out.push('(');
+            write_strings(out, &[key, value]);
+            out.push(')');
+        });
+        out.push(')');
+        out
+    }
+
+    /// A single "out" output with a hash known in advance, e.g. from fetchurl
+    pub fn is_fixed_output(&self) -> bool {
+        match &self.outputs[..] {
+            [output] => output.key == "out" && output.kind() == OutputKind::Fixed,
+            _ => false
+        }
+    }
+
+    /// Fetches sources instead of building anything, so missing it costs a download
+    pub fn is_upstream_fetch(&self) -> bool {
+        self.is_fixed_output() || self.builder == "builtin:fetchurl"
+    }
+
+    /// Whether Nix will substitute outputs of this derivation at all, see Attrs::allows_substitutes
+    pub fn allows_substitutes(&self) -> bool {
+        self.attrs().allows_substitutes()
+    }
+
+    /// Nix's hashDerivationModulo. Input derivations are replaced by their own hash,
+    /// which for fixed-output derivations only depends on the output, so e.g. changing
+    /// a fetchurl mirror doesn't change anything depending on it.
+    /// Hashes of input derivations are memoized in known.
+    pub fn hash_modulo(&self, store: &StoreCache, known: &mut HashMap<StoreHash, [u8; 32]>,
+                       mask_outputs: bool) -> Result<[u8; 32], Error> {
+        if self.is_fixed_output() {
+            let output = &self.outputs[0];
+            let fingerprint = format!("fixed:out:{}:{}:{}", output.hash_algo, output.hash, output.path);
+            return Ok(sha256(fingerprint.as_bytes()))
+        }
+
+        // different derivations can have the same hash, their outputs are merged
+        let mut inputs: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for input in &self.input_drvs {
+            let hash = StoreHash::from_path(&input.path)?;
+            let input_hash = match known.get(&hash) {
+                Some(input_hash) => *input_hash,
+                None => {
+                    let input_drv = match store.get(&hash) {
+                        Some(StoreItem::Drv(drv)) => drv,
+                        _ => return Err(Error::MissingDrv(input.path.clone()))
+                    };
+                    let input_hash = input_drv.hash_modulo(store, known, false)?;
+                    known.insert(hash, input_hash);
+                    input_hash
+                }
+            };
+            inputs.entry(to_hex(&input_hash)).or_default().extend(input.outputs.iter().cloned());
+        }
+
+        let mut drv = self.clone();
+        drv.input_drvs = inputs.into_iter()
+            .map(|(path, outputs)| InputDrv { path, outputs: outputs.into_iter().collect() })
+            .collect();
+
+        // output paths can't depend on themselves
+        if mask_outputs {
+            for output in &mut drv.outputs { output.path.clear() }
+            for (key, value) in &mut drv.env {
+                if self.find_output(key).is_some() { value.clear() }
+            }
+        }
+
+        Ok(sha256(drv.to_aterm().as_bytes()))
+    }
+
+    /// Where Nix would put each output, by output name. Only outputs whose path
+    /// is known before building, input-addressed and fixed ones, are included.
+    pub fn compute_output_paths(&self, store: &StoreCache, known: &mut HashMap<StoreHash, [u8; 32]>)
+            -> Result<Vec<(String, String)>, Error> {
+        let name = self.find_name();
+        if self.is_fixed_output() {
+            return Ok(fixed_output_path(&self.outputs[0], &name)
+                .map(|path| (self.outputs[0].key.clone(), path))
+                .into_iter().collect())
+        }
+
+        // content-addressed or deferred, only known after building
+        if self.outputs.iter().any(|output| output.kind() != OutputKind::InputAddressed) {
+            return Ok(Vec::new())
+        }
+
+        let hash = self.hash_modulo(store, known, true)?;
+        Ok(self.outputs.iter()
+            .map(|DrvOutput { key, .. }| {
+                let name = if key == "out" { name.clone() } else { format!("{}-{}", name, key) };
+                (key.clone(), make_store_path(&format!("output:{}", key), &hash, &name))
+            })
+            .collect())
+    }
+
+    /// The store path Nix would give this .drv: text-addressed by its ATerm,
+    /// with sources and input derivations as references
+    pub fn compute_drv_path(&self) -> String {
+        let mut references: Vec<&str> = self.input_srcs.iter()
+            .chain(self.input_drvs.iter().map(|input| &input.path))
+            .map(String::as_str)
+            .collect();
+        references.sort_unstable();
+        references.dedup();
+
+        let mut kind = String::from("text");
+        for reference in references {
+            kind.push(':');
+            kind.push_str(reference);
+        }
+        make_store_path(&kind, &sha256(self.to_aterm().as_bytes()), &format!("{}.drv", self.find_name()))
+    }
+}
+
+// Where a fixed output goes, e.g. hash_algo = "r:sha256" for recursive (NAR) hashes
+fn fixed_output_path(output: &DrvOutput, name: &str) -> Option<String> {
+    let (method, algo) = match output.hash_algo.strip_prefix("r:") {
+        Some(algo) => ("r:", algo),
+        None => ("", &output.hash_algo[..])
+    };
+    if algo.contains(':') { return None }
+    make_fixed_output_path(method, algo, &from_hex(&output.hash)?, "", name)
+}
+
+// Only these are escaped by Nix, everything else is written verbatim
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+fn write_strings(out: &mut String, strings: &[&str]) {
+    for (i, s) in strings.iter().enumerate() {
+        if i > 0 { out.push(',') }
+        write_string(out, s);
+    }
+}
+
+fn write_list<I, F>(out: &mut String, items: I, mut write_item: F)
+where I: IntoIterator, F: FnMut(&mut String, I::Item) {
+    out.push('[');
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 { out.push(',') }
+        write_item(out, item);
+    }
+    out.push(']');
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -62,6 +279,92 @@ pub struct DrvOutput {
     pub hash: String
 }
 
+/// Derivation attributes, read the same way whether they were passed
+/// as environment variables, or as JSON with __structuredAttrs
+#[derive(Debug)]
+pub enum Attrs<'a> {
+    Env(&'a [(String, String)]),
+    Structured(Cow<'a, serde_json::Map<String, serde_json::Value>>)
+}
+
+impl Attrs<'_> {
+    pub fn name(&self) -> String {
+        self.get_str("name")
+            .map(str::to_owned)
+            .unwrap_or_else(|| String::from("unknown"))
+    }
+
+    /// Trivial builders like writeText set allowSubstitutes = false, since building is cheaper
+    /// than fetching. preferLocalBuild only affects remote builders, and doesn't prevent substitution
+    pub fn allows_substitutes(&self) -> bool {
+        self.get_bool("allowSubstitutes").unwrap_or(true)
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        match self {
+            Attrs::Env(env) => env.iter().find(|(k, _)| k == name).map(|(_, v)| &v[..]),
+            Attrs::Structured(attrs) => attrs.get(name)?.as_str()
+        }
+    }
+
+    /// Nix passes true as "1" and false as "" in the environment
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        match self {
+            Attrs::Env(_) => self.get_str(name).map(|value| value == "1"),
+            Attrs::Structured(attrs) => attrs.get(name)?.as_bool()
+        }
+    }
+
+    /// Lists are space-separated in the environment
+    pub fn get_strings(&self, name: &str) -> Option<Vec<&str>> {
+        match self {
+            Attrs::Env(_) => self.get_str(name).map(|value| value.split_whitespace().collect()),
+            Attrs::Structured(attrs) => attrs.get(name)?.as_array()?.iter().map(|value| value.as_str()).collect()
+        }
+    }
+
+    /// Anything else, e.g. outputChecks, which only exists with __structuredAttrs.
+    /// Environment variables are always strings.
+    pub fn get_json(&self, name: &str) -> Option<serde_json::Value> {
+        match self {
+            Attrs::Env(_) => self.get_str(name).map(|value| serde_json::Value::String(value.to_owned())),
+            Attrs::Structured(attrs) => attrs.get(name).cloned()
+        }
+    }
+}
+
+/// How the path of an output is determined, following Nix's DerivationOutput
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    /// Path depends on the derivation, known in advance
+    InputAddressed,
+    /// Path depends on a hash given in advance, e.g. by fetchurl
+    Fixed,
+    /// Content-addressed, path only known after building
+    Floating,
+    /// Input-addressed, but depends on floating outputs, so the path isn't known yet
+    Deferred,
+    /// Content-addressed, but may differ on every build
+    Impure
+}
+
+impl DrvOutput {
+    pub fn kind(&self) -> OutputKind {
+        match (&self.hash_algo[..], &self.hash[..], &self.path[..]) {
+            ("", _, "") => OutputKind::Deferred,
+            ("", _, _) => OutputKind::InputAddressed,
+            (_, "impure", _) => OutputKind::Impure,
+            (_, "", _) => OutputKind::Floating,
+            _ => OutputKind::Fixed
+        }
+    }
+
+    /// Whether the path is recorded in the derivation, rather than only known after building
+    pub fn has_path(&self) -> bool {
+        matches!(self.kind(), OutputKind::InputAddressed | OutputKind::Fixed)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InputDrv {
     pub path: String,
@@ -69,14 +372,51 @@ pub struct InputDrv {
 }
 
 impl InputDrv {
-    // TODO: don't lifetime *everything*?
-    pub fn resolve<'a>(&'a self, drvs: &'a StoreCache) -> impl Iterator<Item = &'a str> + 'a {
-        let hash = StoreHash::from_path(&self.path);
-        if let Some(StoreItem::Drv(drv)) = drvs.get(&hash) {
-            self.outputs.iter()
-                .flat_map(move |output| drv.find_output(output))
-                .map(|output| &output.path[..])
-        } else { panic!() }
+    /// Hashes of the selected outputs, see StoreCache::outputs_of
+    pub fn resolve(&self, store: &StoreCache) -> Result<Vec<StoreHash>, Error> {
+        let hash = StoreHash::from_path(&self.path)?;
+        match store.get(&hash) {
+            Some(StoreItem::Drv(drv)) => Ok(store.outputs_of(hash, drv, self.outputs.iter().map(String::as_str))),
+            // only outputs that turned up in narinfos are known
+            Some(StoreItem::UnknownDrv(_)) => Ok(self.outputs.iter()
+                .map(|key| store.discovered_output(hash, key).unwrap_or(hash))
+                .collect()),
+            _ => Err(Error::MissingDrv(self.path.clone()))
+        }
+    }
+}
+
+/// Outputs selected on an installable, like /nix/store/...-hello.drv^out,man
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputsSpec {
+    All,
+    Names(Vec<String>)
+}
+
+impl OutputsSpec {
+    /// Splits off ^out,man (or !out,man, as older Nix wrote it) after a .drv path,
+    /// selecting all outputs without it or with *
+    pub fn split(installable: &str) -> Result<(&str, OutputsSpec), Error> {
+        let (path, outputs) = match installable.rfind(&['^', '!'][..]) {
+            Some(i) if installable[..i].ends_with(".drv") => (&installable[..i], &installable[i + 1..]),
+            _ => return Ok((installable, OutputsSpec::All))
+        };
+
+        if outputs == "*" { return Ok((path, OutputsSpec::All)) }
+        let keys: Vec<_> = outputs.split(',')
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if keys.is_empty() { return Err(Error::InvalidOutputsSpec(installable.to_owned())) }
+        Ok((path, OutputsSpec::Names(keys)))
+    }
+
+    /// Keys of the selected outputs of drv, which might not all exist
+    pub fn keys<'a>(&'a self, drv: &'a Drv) -> Vec<&'a str> {
+        match self {
+            OutputsSpec::All => drv.outputs.iter().map(|output| &output.key[..]).collect(),
+            OutputsSpec::Names(keys) => keys.iter().map(String::as_str).collect()
+        }
     }
 }
 
@@ -88,6 +428,7 @@ fn string(i: &[u8]) -> IResult<&[u8], String> {
               value(&b"\\"[..], char('\\')),
               value(&b"\""[..], char('"')),
               value(&b"\n"[..], char('n')),
+              value(&b"\r"[..], char('r')),
               value(&b"\t"[..], char('t'))
             ))),
             |bytes| String::from_utf8_lossy(&bytes).into_owned()
@@ -160,6 +501,35 @@ fn drv(i: &[u8]) -> IResult<&[u8], Drv> {
     )(i)
 }
 
+/// A derivation building the output path /nix/store/<name> with /bin/sh, for tests
+#[cfg(test)]
+pub(crate) fn test_drv(name: &str, input_drvs: &[&str]) -> Drv {
+    Drv {
+        outputs: vec![DrvOutput {
+            key: String::from("out"),
+            path: format!("/nix/store/{}", name),
+            hash_algo: String::new(),
+            hash: String::new()
+        }],
+        input_drvs: input_drvs.iter()
+            .map(|path| InputDrv { path: format!("/nix/store/{}", path), outputs: vec![String::from("out")] })
+            .collect(),
+        input_srcs: Vec::new(),
+        platform: String::from("x86_64-linux"),
+        builder: String::from("/bin/sh"),
+        builder_args: Vec::new(),
+        env: vec![(String::from("name"), name[33..].to_owned())]
+    }
+}
+
+/// derivation { name = "myname"; builder = "mybuilder"; system = "mysystem"; }, as instantiated
+/// by Nix, along with its path
+#[cfg(test)]
+pub(crate) fn test_myname() -> (&'static str, Drv) {
+    let (_, myname) = drv(include_bytes!("../assets/myname.drv")).unwrap();
+    ("/nix/store/z3hhlxbckx4g3n9sw91nnvlkjvyw754p-myname.drv", myname)
+}
+
 #[test]
 fn parse_string() {
     assert_eq!(string(br#""foo""#), Ok((&b""[..], String::from("foo"))));
@@ -169,6 +539,7 @@ fn parse_string() {
     assert_eq!(string(br#""\\""#), Ok((&b""[..], String::from("\\"))));
     assert_eq!(string(br#""\t""#), Ok((&b""[..], String::from("\t"))));
     assert_eq!(string(br#""\n""#), Ok((&b""[..], String::from("\n"))));
+    assert_eq!(string(br#""\r""#), Ok((&b""[..], String::from("\r"))));
 }
 
 #[test]
@@ -217,3 +588,185 @@ fn parse_derivation() {
     println!("{:?}", drv_xz);
     assert!(drv_xz.is_ok());
 }
+
+#[test]
+fn read_missing_derivation() {
+    let missing = Drv::read_from("/nix/store/00000000000000000000000000000000-missing.drv");
+    assert!(matches!(missing, Err(Error::ReadDrv(_, _))));
+
+    let garbage = Drv::read_from(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/dejagnu.narinfo"));
+    assert!(matches!(garbage, Err(Error::ParseDrv(_))));
+}
+
+#[test]
+fn write_string_escapes() {
+    let mut out = String::new();
+    write_string(&mut out, "a\"b\\c\nd\re\tf$g");
+    assert_eq!(out, r#""a\"b\\c\nd\re\tf$g""#);
+    assert_eq!(string(out.as_bytes()), Ok((&b""[..], String::from("a\"b\\c\nd\re\tf$g"))));
+}
+
+#[test]
+fn derivation_roundtrip() {
+    let fixtures: [&[u8]; 3] = [
+        include_bytes!("../assets/hello.drv"),
+        include_bytes!("../assets/blender.drv"),
+        include_bytes!("../assets/xz.tar.bz2.drv")
+    ];
+    for fixture in &fixtures {
+        let (_, parsed) = drv(fixture).unwrap();
+        assert_eq!(parsed.to_aterm().as_bytes(), *fixture);
+    }
+
+    // written in canonical order, regardless of the order in memory
+    let (_, mut parsed) = drv(include_bytes!("../assets/hello.drv")).unwrap();
+    parsed.env.reverse();
+    parsed.input_drvs.reverse();
+    assert_eq!(parsed.to_aterm().as_bytes(), &include_bytes!("../assets/hello.drv")[..]);
+}
+
+#[test]
+fn compute_fixed_output_path() {
+    let (_, mut xz) = drv(include_bytes!("../assets/xz.tar.bz2.drv")).unwrap();
+    assert!(xz.is_fixed_output());
+    assert!(xz.is_upstream_fetch());
+    assert_eq!(to_base32(&from_hex(&xz.outputs[0].hash).unwrap()),
+               xz.env.iter().find(|(key, _)| key == "outputHash").unwrap().1);
+
+    let paths = xz.compute_output_paths(&StoreCache::default(), &mut HashMap::new()).unwrap();
+    assert_eq!(paths, vec![(String::from("out"), xz.outputs[0].path.clone())]);
+
+    xz.outputs[0].hash.clear();
+    assert!(!xz.is_fixed_output());
+    assert!(xz.is_upstream_fetch());
+}
+
+#[test]
+fn compute_input_addressed_output_paths() {
+    let (_, xz) = drv(include_bytes!("../assets/xz.tar.bz2.drv")).unwrap();
+    let xz_path = "/nix/store/fbx9ysbd6wplnkwk46a6hbafkqp5m5w5-xz-5.2.4.tar.bz2.drv";
+    let mut store = StoreCache::default();
+    store.discover_build_time_closure(StoreHash::from_path(xz_path).unwrap(), &xz).unwrap();
+
+    let mut unpack = test_drv("00000000000000000000000000000000-xz", &[&xz_path["/nix/store/".len()..]]);
+    unpack.outputs[0].path.clear();
+    unpack.outputs.push(DrvOutput { key: String::from("doc"), ..unpack.outputs[0].clone() });
+    unpack.env = vec![(String::from("doc"), String::new()), (String::from("name"), String::from("xz")),
+                      (String::from("out"), String::new())];
+    let mut known = HashMap::new();
+    // not known before building
+    assert!(unpack.compute_output_paths(&store, &mut known).unwrap().is_empty());
+
+    // pretend to be Nix, filling in the paths it would compute
+    for output in &mut unpack.outputs { output.path = format!("/nix/store/{}", output.key) }
+    let paths = unpack.compute_output_paths(&store, &mut known).unwrap();
+    for (output, (key, path)) in unpack.outputs.iter_mut().zip(&paths) {
+        assert_eq!(&output.key, key);
+        output.path = path.clone();
+    }
+    assert!(paths[1].1.ends_with("-xz-doc"));
+    assert_eq!(unpack.compute_output_paths(&store, &mut known).unwrap(), paths);
+
+    // the input is hashed by its output, not by its own store path
+    unpack.input_drvs[0].path = String::from("/nix/store/00000000000000000000000000000000-xz-5.2.4.tar.bz2.drv");
+    store.discover_build_time_closure(StoreHash::from_path(&unpack.input_drvs[0].path).unwrap(), &xz).unwrap();
+    assert_eq!(unpack.compute_output_paths(&store, &mut HashMap::new()).unwrap(), paths);
+}
+
+#[test]
+fn compute_drv_path() {
+    let (path, mut myname) = test_myname();
+    assert_eq!(myname.compute_drv_path(), path);
+    assert_eq!(myname.compute_output_paths(&StoreCache::default(), &mut HashMap::new()).unwrap(),
+               vec![(String::from("out"), String::from("/nix/store/40s0qmrfb45vlh6610rk29ym318dswdr-myname"))]);
+
+    // the path only depends on the canonical form
+    myname.env.reverse();
+    assert_eq!(myname.compute_drv_path(), path);
+    myname.env.push((String::from("extra"), String::new()));
+    assert_ne!(myname.compute_drv_path(), path);
+}
+
+#[test]
+fn classify_outputs() {
+    let output = |path: &str, hash_algo: &str, hash: &str| DrvOutput {
+        key: String::from("out"),
+        path: path.to_owned(),
+        hash_algo: hash_algo.to_owned(),
+        hash: hash.to_owned()
+    };
+    let path = "/nix/store/rgmc4d3spji36n2l1sicm80yq79dpcc2-hello-2.10";
+    assert_eq!(output(path, "", "").kind(), OutputKind::InputAddressed);
+    assert_eq!(output(path, "r:sha256", "3313fd2a").kind(), OutputKind::Fixed);
+    assert_eq!(output("", "r:sha256", "").kind(), OutputKind::Floating);
+    assert_eq!(output("", "", "").kind(), OutputKind::Deferred);
+    assert_eq!(output("", "r:sha256", "impure").kind(), OutputKind::Impure);
+    assert!(!output("", "r:sha256", "").has_path());
+}
+
+#[test]
+fn read_bool_attrs() {
+    let (_, mut hello) = drv(include_bytes!("../assets/hello.drv")).unwrap();
+    assert_eq!(hello.attrs().get_bool("allowSubstitutes"), None);
+    assert!(hello.allows_substitutes());
+
+    hello.env.push((String::from("allowSubstitutes"), String::new()));
+    assert!(!hello.allows_substitutes());
+
+    hello.env = vec![(String::from("__json"), String::from(r#"{"allowSubstitutes":false,"name":"hello"}"#))];
+    assert!(!hello.allows_substitutes());
+
+    // fetchurl prefers local builds, but is substituted all the same
+    let (_, xz) = drv(include_bytes!("../assets/xz.tar.bz2.drv")).unwrap();
+    assert_eq!(xz.attrs().get_bool("preferLocalBuild"), Some(true));
+    assert!(xz.allows_substitutes());
+}
+
+#[test]
+fn read_structured_attrs() {
+    let (_, mut hello) = drv(include_bytes!("../assets/hello.drv")).unwrap();
+    let attrs = hello.attrs();
+    assert!(matches!(attrs, Attrs::Env(_)));
+    assert_eq!(attrs.get_str("version"), Some("2.10"));
+    assert_eq!(attrs.get_strings("buildInputs"), Some(Vec::new()));
+
+    hello.env = vec![
+        (String::from("__json"), String::from(r#"{
+            "name": "hello-2.10",
+            "allowSubstitutes": false,
+            "exportReferencesGraph": { "graph": ["/nix/store/rgmc4d3spji36n2l1sicm80yq79dpcc2-hello-2.10"] },
+            "outputChecks": { "out": { "allowedReferences": [] } },
+            "buildInputs": ["a", "b"]
+        }"#)),
+        (String::from("out"), String::from("/nix/store/rgmc4d3spji36n2l1sicm80yq79dpcc2-hello-2.10"))
+    ];
+    assert_eq!(hello.find_name(), "hello-2.10");
+    let attrs = hello.attrs();
+    assert_eq!(attrs.get_bool("allowSubstitutes"), Some(false));
+    assert_eq!(attrs.get_strings("buildInputs"), Some(vec!["a", "b"]));
+    assert!(attrs.get_json("outputChecks").unwrap()["out"]["allowedReferences"].is_array());
+    assert!(attrs.get_json("exportReferencesGraph").unwrap()["graph"].is_array());
+    // outputs stay in the environment
+    assert_eq!(attrs.get_str("out"), None);
+
+    hello.env = vec![(String::from("__json"), String::from("{"))];
+    assert_eq!(hello.find_name(), "unknown");
+}
+
+#[test]
+fn split_outputs_spec() {
+    let path = "/nix/store/ki3gq0ylxrl54fh4bnd1q1ar0ls4bdi2-hello-2.10.drv";
+    assert_eq!(OutputsSpec::split(path).unwrap(), (path, OutputsSpec::All));
+    assert_eq!(OutputsSpec::split(&format!("{}^*", path)).unwrap(), (path, OutputsSpec::All));
+    assert_eq!(OutputsSpec::split(&format!("{}^out,man", path)).unwrap(),
+               (path, OutputsSpec::Names(vec![String::from("out"), String::from("man")])));
+    assert_eq!(OutputsSpec::split(&format!("{}!dev", path)).unwrap(), (path, OutputsSpec::Names(vec![String::from("dev")])));
+    // only .drv paths have outputs to select
+    assert_eq!(OutputsSpec::split("/home/me/foo!bar/result").unwrap(), ("/home/me/foo!bar/result", OutputsSpec::All));
+    assert!(OutputsSpec::split(&format!("{}^", path)).is_err());
+    assert!(OutputsSpec::split(&format!("{}^,", path)).is_err());
+
+    let drv = Drv::read_from(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/hello.drv")).unwrap();
+    assert_eq!(OutputsSpec::All.keys(&drv), vec!["out"]);
+    assert_eq!(OutputsSpec::Names(vec![String::from("dev")]).keys(&drv), vec!["dev"]);
+}
@@ -0,0 +1,306 @@
+use std::collections::{ BTreeMap, HashMap, HashSet };
+
+use serde_derive::Deserialize;
+use log::trace;
+
+use crate::{ Error, STORE_DIR, derivation::*, nar::{ self, NarEntry } };
+
+/// Where derivations are read from while discovering build-time closures
+pub trait DrvSource {
+    fn read_drv(&self, path: &str) -> Result<Drv, Error>;
+}
+
+/// .drv files in the local store
+pub struct FileSystem;
+
+impl DrvSource for FileSystem {
+    fn read_drv(&self, path: &str) -> Result<Drv, Error> { Drv::read_from(path) }
+}
+
+/// Derivations that were already parsed, by store path
+#[derive(Default, Debug)]
+pub struct DrvMap(HashMap<String, Drv>);
+
+impl DrvSource for DrvMap {
+    fn read_drv(&self, path: &str) -> Result<Drv, Error> {
+        self.0.get(path).cloned().ok_or_else(|| Error::MissingDrv(path.to_owned()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonDump {
+    // newer versions wrap derivations with a format version
+    Versioned { derivations: BTreeMap<String, JsonDrv> },
+    Plain(BTreeMap<String, JsonDrv>)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonDrv {
+    outputs: BTreeMap<String, JsonOutput>,
+    #[serde(default)]
+    input_srcs: Vec<String>,
+    #[serde(default)]
+    input_drvs: BTreeMap<String, JsonInputDrv>,
+    system: String,
+    builder: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonOutput {
+    #[serde(default)]
+    path: Option<String>,
+    // only in newer versions, then hashAlgo doesn't include the r: prefix anymore
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    hash_algo: Option<String>,
+    #[serde(default)]
+    hash: Option<String>
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonInputDrv {
+    Outputs(Vec<String>),
+    // newer versions, dynamicOutputs are ignored
+    Nested { outputs: Vec<String> }
+}
+
+// newer versions leave out the store directory
+fn store_path(path: String) -> String {
+    if path.starts_with('/') { path } else { format!("{}/{}", STORE_DIR, path) }
+}
+
+impl JsonOutput {
+    fn into_output(self, key: String) -> DrvOutput {
+        let algo = self.hash_algo.unwrap_or_default();
+        let hash_algo = match self.method.as_deref() {
+            Some("nar") => format!("r:{}", algo),
+            Some("text") => format!("text:{}", algo),
+            _ => algo
+        };
+        DrvOutput {
+            key,
+            path: self.path.map(store_path).unwrap_or_default(),
+            hash_algo,
+            hash: self.hash.unwrap_or_default()
+        }
+    }
+}
+
+impl From<JsonDrv> for Drv {
+    fn from(drv: JsonDrv) -> Self {
+        Drv {
+            outputs: drv.outputs.into_iter()
+                .map(|(key, output)| output.into_output(key))
+                .collect(),
+            input_drvs: drv.input_drvs.into_iter()
+                .map(|(path, input)| InputDrv {
+                    path: store_path(path),
+                    outputs: match input {
+                        JsonInputDrv::Outputs(outputs) | JsonInputDrv::Nested { outputs } => outputs
+                    }
+                })
+                .collect(),
+            input_srcs: drv.input_srcs.into_iter().map(store_path).collect(),
+            platform: drv.system,
+            builder: drv.builder,
+            builder_args: drv.args,
+            env: drv.env.into_iter().collect()
+        }
+    }
+}
+
+impl DrvMap {
+    pub fn insert(&mut self, path: String, drv: Drv) { self.0.insert(path, drv); }
+
+    /// Reads the output of nix derivation show -r, or nix show-derivation -r in older versions
+    pub fn from_json(json: &[u8]) -> Result<Self, Error> {
+        let dump = serde_json::from_slice(json).map_err(|e| Error::ParseDrvJson(e.to_string()))?;
+        let drvs = match dump { JsonDump::Versioned { derivations } | JsonDump::Plain(derivations) => derivations };
+
+        let mut map = DrvMap::default();
+        for (path, drv) in drvs {
+            trace!("loaded derivation {}", path);
+            map.insert(store_path(path), drv.into());
+        }
+        Ok(map)
+    }
+
+    /// Reads .drv files from nix-store --export output, or from a NAR of a directory containing them
+    pub fn from_archive(bytes: &[u8]) -> Result<Self, Error> {
+        let mut map = DrvMap::default();
+        if nar::is_nar(bytes) {
+            let root = nar::read_nar(&mut &bytes[..]).map_err(Error::ReadNar)?;
+            map.insert_drvs_from(&root)?;
+        } else {
+            for exported in nar::read_export(&mut &bytes[..]).map_err(Error::ReadNar)? {
+                match exported.nar {
+                    NarEntry::Regular { contents, .. } if exported.path.ends_with(".drv") => {
+                        trace!("loaded derivation {}", exported.path);
+                        let drv = Drv::parse(&exported.path, &contents)?;
+                        map.insert(exported.path, drv);
+                    }
+                    _ => trace!("ignoring exported {}", exported.path)
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    // .drv files anywhere in the tree, named like in the store
+    fn insert_drvs_from(&mut self, entry: &NarEntry) -> Result<(), Error> {
+        if let NarEntry::Directory(entries) = entry {
+            for (name, entry) in entries {
+                match entry {
+                    NarEntry::Regular { contents, .. } if name.ends_with(".drv") => {
+                        let path = store_path(name.clone());
+                        trace!("loaded derivation {}", path);
+                        let drv = Drv::parse(&path, contents)?;
+                        self.insert(path, drv);
+                    }
+                    NarEntry::Directory(_) => self.insert_drvs_from(entry)?,
+                    _ => ()
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Derivations no other derivation depends on, like the ones nix derivation show -r was called with
+    pub fn roots(&self) -> Vec<String> {
+        let inputs: HashSet<&str> = self.0.values()
+            .flat_map(|drv| drv.input_drvs.iter().map(|input| &input.path[..]))
+            .collect();
+        let mut roots: Vec<String> = self.0.keys()
+            .filter(|path| !inputs.contains(&path[..]))
+            .cloned()
+            .collect();
+        roots.sort();
+        roots
+    }
+}
+
+#[test]
+fn load_old_json() {
+    let map = DrvMap::from_json(br#"{
+      "/nix/store/fbx9ysbd6wplnkwk46a6hbafkqp5m5w5-xz-5.2.4.tar.bz2.drv": {
+        "outputs": {
+          "out": {
+            "path": "/nix/store/id71yr7x2cbrrr8pb51qf2jwjq19rkfq-xz-5.2.4.tar.bz2",
+            "hashAlgo": "sha256",
+            "hash": "3313fd2a95f43d88e44264e6b015e7d03053e681860b0d5d3f9baca79c57b7bf"
+          }
+        },
+        "inputSrcs": [],
+        "inputDrvs": {},
+        "system": "builtin",
+        "builder": "builtin:fetchurl",
+        "args": [],
+        "env": {
+          "builder": "builtin:fetchurl",
+          "executable": "",
+          "impureEnvVars": "http_proxy https_proxy ftp_proxy all_proxy no_proxy",
+          "name": "xz-5.2.4.tar.bz2",
+          "out": "/nix/store/id71yr7x2cbrrr8pb51qf2jwjq19rkfq-xz-5.2.4.tar.bz2",
+          "outputHash": "1gxpayfagb4v7xfhs2w6h7k56c6hwwav1rk48bj8hggljlmgs4rk",
+          "outputHashAlgo": "sha256",
+          "outputHashMode": "flat",
+          "preferLocalBuild": "1",
+          "system": "builtin",
+          "unpack": "",
+          "url": "https://tukaani.org/xz/xz-5.2.4.tar.bz2",
+          "urls": "https://tukaani.org/xz/xz-5.2.4.tar.bz2"
+        }
+      },
+      "/nix/store/0ypgbvpjvw0ad6ad1bp8zxwkqr2f1ymy-xz-5.2.4.drv": {
+        "outputs": { "out": { "path": "/nix/store/gmc2n5hy3blrm6r43yvmzjfpxbjq8ydz-xz-5.2.4" } },
+        "inputSrcs": [],
+        "inputDrvs": { "/nix/store/fbx9ysbd6wplnkwk46a6hbafkqp5m5w5-xz-5.2.4.tar.bz2.drv": ["out"] },
+        "system": "x86_64-linux",
+        "builder": "/bin/sh",
+        "args": [],
+        "env": { "name": "xz-5.2.4" }
+      }
+    }"#).unwrap();
+
+    let xz = map.read_drv("/nix/store/fbx9ysbd6wplnkwk46a6hbafkqp5m5w5-xz-5.2.4.tar.bz2.drv").unwrap();
+    assert_eq!(xz.to_aterm().as_bytes(), &include_bytes!("../assets/xz.tar.bz2.drv")[..]);
+    assert_eq!(map.roots(), vec!["/nix/store/0ypgbvpjvw0ad6ad1bp8zxwkqr2f1ymy-xz-5.2.4.drv"]);
+    assert!(matches!(map.read_drv("/nix/store/00000000000000000000000000000000-missing.drv"),
+                     Err(Error::MissingDrv(_))));
+}
+
+#[test]
+fn load_new_json() {
+    let map = DrvMap::from_json(br#"{
+      "version": 4,
+      "derivations": {
+        "0ypgbvpjvw0ad6ad1bp8zxwkqr2f1ymy-xz-5.2.4.drv": {
+          "name": "xz-5.2.4",
+          "outputs": {
+            "out": { "method": "nar", "hashAlgo": "sha256" },
+            "src": { "path": "id71yr7x2cbrrr8pb51qf2jwjq19rkfq-src", "method": "flat", "hashAlgo": "sha256", "hash": "3313fd2a" }
+          },
+          "inputSrcs": ["9krlzvny65gdc8s7kpb6lkx8cd02c25b-default-builder.sh"],
+          "inputDrvs": {
+            "cif7s5k57iwcxwgcv01myyiypw1skz99-stdenv-linux.drv": { "outputs": ["out"], "dynamicOutputs": {} }
+          },
+          "system": "x86_64-linux",
+          "builder": "/bin/sh",
+          "args": ["-e"],
+          "env": { "name": "xz-5.2.4" }
+        }
+      }
+    }"#).unwrap();
+
+    let xz = map.read_drv("/nix/store/0ypgbvpjvw0ad6ad1bp8zxwkqr2f1ymy-xz-5.2.4.drv").unwrap();
+    let out = xz.find_output("out").unwrap();
+    assert_eq!(out.kind(), OutputKind::Floating);
+    assert_eq!(out.hash_algo, "r:sha256");
+    assert_eq!(xz.find_output("src").unwrap().path, "/nix/store/id71yr7x2cbrrr8pb51qf2jwjq19rkfq-src");
+    assert_eq!(xz.input_srcs, vec!["/nix/store/9krlzvny65gdc8s7kpb6lkx8cd02c25b-default-builder.sh"]);
+    assert_eq!(xz.input_drvs[0].path, "/nix/store/cif7s5k57iwcxwgcv01myyiypw1skz99-stdenv-linux.drv");
+    assert_eq!(xz.input_drvs[0].outputs, vec!["out"]);
+}
+
+#[test]
+fn load_archives() {
+    use crate::nar::{ ExportedPath, write_export, write_nar };
+
+    let xz_path = "/nix/store/fbx9ysbd6wplnkwk46a6hbafkqp5m5w5-xz-5.2.4.tar.bz2.drv";
+    let xz = NarEntry::Regular { executable: false, contents: include_bytes!("../assets/xz.tar.bz2.drv").to_vec() };
+
+    let mut export = Vec::new();
+    write_export(&mut export, &[
+        ExportedPath { path: xz_path.to_owned(), nar: xz.clone(), references: Vec::new(), deriver: None },
+        ExportedPath {
+            path: String::from("/nix/store/id71yr7x2cbrrr8pb51qf2jwjq19rkfq-xz-5.2.4.tar.bz2"),
+            nar: NarEntry::Regular { executable: false, contents: Vec::new() },
+            references: Vec::new(),
+            deriver: Some(xz_path.to_owned())
+        }
+    ]);
+    let map = DrvMap::from_archive(&export).unwrap();
+    assert_eq!(map.roots(), vec![xz_path]);
+    assert_eq!(map.read_drv(xz_path).unwrap().find_name(), "xz-5.2.4.tar.bz2");
+
+    let mut nar = Vec::new();
+    write_nar(&mut nar, &NarEntry::Directory(vec![
+        (String::from("drvs"), NarEntry::Directory(vec![
+            (String::from("fbx9ysbd6wplnkwk46a6hbafkqp5m5w5-xz-5.2.4.tar.bz2.drv"), xz)
+        ])),
+        (String::from("README"), NarEntry::Regular { executable: false, contents: Vec::new() })
+    ]));
+    let map = DrvMap::from_archive(&nar).unwrap();
+    assert_eq!(map.roots(), vec![xz_path]);
+
+    assert!(matches!(DrvMap::from_archive(&nar[..nar.len() - 8]), Err(Error::ReadNar(_))));
+}
@@ -0,0 +1,67 @@
+use std::{ fmt, io, path::PathBuf };
+
+#[derive(Debug)]
+pub enum Error {
+    /// A derivation couldn't be read, e.g. because it was garbage-collected
+    ReadDrv(PathBuf, io::Error),
+    /// A derivation was read, but isn't valid ATerm
+    ParseDrv(PathBuf),
+    /// A derivation dump from nix derivation show couldn't be read
+    ParseDrvJson(String),
+    /// A list of inputs couldn't be read
+    ReadInputList(PathBuf, io::Error),
+    /// A list of inputs, like nix-eval-jobs output, couldn't be parsed
+    ParseInputList(String),
+    /// A NAR or nix-store --export stream is truncated or malformed
+    ReadNar(io::Error),
+    /// Not of the form <hash>-<name>, or the hash isn't a Nix store hash
+    InvalidStorePath(String),
+    /// Selects no outputs, like hello.drv^
+    InvalidOutputsSpec(String),
+    /// An input derivation hasn't been registered in the StoreCache
+    MissingDrv(String),
+    /// A .narinfo is malformed or lacks required fields
+    ParseNarInfo(String),
+    /// A realisation of a content-addressed output isn't valid JSON
+    ParseRealisation(String),
+    /// Not of the form <name>:<base64 ed25519 key>
+    InvalidPublicKey(String),
+    /// A binary cache URL that can't be queried
+    InvalidCache(String),
+    /// A .narinfo couldn't be fetched, even after retrying
+    Fetch(String, reqwest::Error),
+    /// A .narinfo exists in a local binary cache, but couldn't be read
+    ReadCache(PathBuf, io::Error)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ReadDrv(path, e) => write!(f, "unable to read derivation {}: {}", path.display(), e),
+            Error::ParseDrv(path) => write!(f, "unable to parse derivation {}", path.display()),
+            Error::ParseDrvJson(reason) => write!(f, "unable to parse derivation JSON: {}", reason),
+            Error::ReadInputList(path, e) => write!(f, "unable to read input list {}: {}", path.display(), e),
+            Error::ParseInputList(reason) => write!(f, "unable to parse input list: {}", reason),
+            Error::ReadNar(e) => write!(f, "unable to read NAR: {}", e),
+            Error::InvalidStorePath(path) => write!(f, "invalid store path {}", path),
+            Error::InvalidOutputsSpec(installable) => write!(f, "no outputs selected in {}", installable),
+            Error::MissingDrv(path) => write!(f, "derivation {} is unknown", path),
+            Error::ParseNarInfo(reason) => write!(f, "unable to parse narinfo: {}", reason),
+            Error::ParseRealisation(reason) => write!(f, "unable to parse realisation: {}", reason),
+            Error::InvalidPublicKey(key) => write!(f, "invalid public key {}", key),
+            Error::InvalidCache(url) => write!(f, "unsupported binary cache {}", url),
+            Error::Fetch(url, e) => write!(f, "unable to fetch {}: {}", url, e),
+            Error::ReadCache(path, e) => write!(f, "unable to read {}: {}", path.display(), e)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ReadDrv(_, e) | Error::ReadCache(_, e) | Error::ReadInputList(_, e) | Error::ReadNar(e) => Some(e),
+            Error::Fetch(_, e) => Some(e),
+            _ => None
+        }
+    }
+}
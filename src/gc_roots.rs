@@ -0,0 +1,94 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{ Path, PathBuf }
+};
+
+use log::{ debug, warn };
+
+use crate::STORE_DIR;
+
+pub const STATE_DIR: &str = "/nix/var/nix";
+
+/// Store paths kept alive by symlinks in gcroots and profiles, like nix-store --gc --print-roots,
+/// each with the links pointing to it
+pub fn find_roots<P: AsRef<Path>>(state_dir: P) -> BTreeMap<String, Vec<PathBuf>> {
+    let mut roots = BTreeMap::new();
+    for dir in &["gcroots", "profiles"] {
+        find_roots_in(&state_dir.as_ref().join(dir), &mut roots);
+    }
+    for links in roots.values_mut() {
+        links.sort();
+        links.dedup();
+    }
+    roots
+}
+
+// The top-level store path of e.g. /nix/store/<hash>-system/sw/bin
+fn to_store_path(target: &Path) -> Option<String> {
+    let name = target.strip_prefix(STORE_DIR).ok()?.components().next()?;
+    Some(format!("{}/{}", STORE_DIR, name.as_os_str().to_string_lossy()))
+}
+
+fn find_roots_in(path: &Path, roots: &mut BTreeMap<String, Vec<PathBuf>>) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => { warn!("unable to read {}: {}", path.display(), e); return }
+    };
+
+    if metadata.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => { warn!("unable to read {}: {}", path.display(), e); return }
+        };
+        for entry in entries.filter_map(Result::ok) {
+            find_roots_in(&entry.path(), roots);
+        }
+    } else if metadata.file_type().is_symlink() {
+        let target = match fs::read_link(path) {
+            Ok(target) => target,
+            Err(e) => { warn!("unable to read {}: {}", path.display(), e); return }
+        };
+
+        if let Some(store_path) = to_store_path(&target) {
+            roots.entry(store_path).or_default().push(path.to_owned());
+            return
+        }
+
+        // Indirect roots, like gcroots/auto/<hash> -> /home/user/result -> /nix/store/...
+        let indirect = path.parent().map(|parent| parent.join(&target)).unwrap_or(target);
+        match fs::read_link(&indirect).ok().as_deref().and_then(to_store_path) {
+            Some(store_path) => roots.entry(store_path).or_default().push(indirect),
+            None => debug!("ignoring {}, which doesn't point into the store", path.display())
+        }
+    }
+}
+
+#[test]
+fn find_gc_roots() {
+    use std::os::unix::fs::symlink;
+
+    let state_dir = std::env::temp_dir().join(format!("nix-weather-state-{}", std::process::id()));
+    let (gcroots, profiles, home) = (state_dir.join("gcroots"), state_dir.join("profiles"), state_dir.join("home"));
+    for dir in &[gcroots.join("auto"), profiles.clone(), home.clone()] {
+        fs::create_dir_all(dir).unwrap();
+    }
+
+    let system = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let result = "/nix/store/22222222222222222222222222222222-hello-2.10";
+    symlink(format!("{}/sw/bin", system), gcroots.join("booted-system")).unwrap();
+    symlink(system, profiles.join("system-42-link")).unwrap();
+    symlink("system-42-link", profiles.join("system")).unwrap();
+    symlink(result, home.join("result")).unwrap();
+    symlink(home.join("result"), gcroots.join("auto").join("3s8l7kz4wxxn7ja1kp1aaj1m4y9an2yz")).unwrap();
+    // the result was deleted, so the root is stale
+    symlink(home.join("gone"), gcroots.join("auto").join("1mjfnsxlbfkjgnjwyfn2kplzxw38rbjw")).unwrap();
+
+    let roots = find_roots(&state_dir);
+    assert_eq!(roots.keys().collect::<Vec<_>>(), vec![system, result]);
+    assert_eq!(roots[system], vec![gcroots.join("booted-system"), profiles.join("system-42-link")]);
+    assert_eq!(roots[result], vec![home.join("result")]);
+
+    fs::remove_dir_all(&state_dir).unwrap();
+}
@@ -0,0 +1,94 @@
+use sha2::{ Digest, Sha256 };
+
+use crate::STORE_DIR;
+
+// Nix's own base32 alphabet, which omits e, o, u and t
+const BASE32_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+pub fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 || !hex.is_ascii() { return None }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Nix's base32, which starts with the last bytes instead of the first
+pub fn to_base32(hash: &[u8]) -> String {
+    let len = (hash.len() * 8 - 1) / 5 + 1;
+    (0..len).rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let low = hash[i] as u16 >> j;
+            let high = hash.get(i + 1).map_or(0, |&byte| (byte as u16) << (8 - j));
+            BASE32_CHARS[((low | high) & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+/// Inverse of to_base32, rejecting bits that don't fit into the hash
+pub fn from_base32(base32: &str) -> Option<Vec<u8>> {
+    let mut hash = vec![0u8; base32.len() * 5 / 8];
+    for (n, c) in base32.bytes().rev().enumerate() {
+        let digit = BASE32_CHARS.iter().position(|&b| b == c)? as u16;
+        let (i, bits) = (n * 5 / 8, digit << (n * 5 % 8));
+        for (byte, &bits) in (i..i + 2).zip([bits & 0xff, bits >> 8].iter()) {
+            match hash.get_mut(byte) {
+                Some(byte) => *byte |= bits as u8,
+                None if bits != 0 => return None,
+                None => ()
+            }
+        }
+    }
+    Some(hash)
+}
+
+/// XOR-folds hash into size bytes, like Nix's compressHash
+pub fn compress_hash(hash: &[u8], size: usize) -> Vec<u8> {
+    let mut compressed = vec![0; size];
+    for (i, byte) in hash.iter().enumerate() {
+        compressed[i % size] ^= byte;
+    }
+    compressed
+}
+
+/// Nix's makeStorePath, e.g. kind = "output:out" for input-addressed outputs
+pub fn make_store_path(kind: &str, hash: &[u8], name: &str) -> String {
+    let fingerprint = format!("{}:sha256:{}:{}:{}", kind, to_hex(hash), STORE_DIR, name);
+    let hash = compress_hash(&sha256(fingerprint.as_bytes()), 20);
+    format!("{}/{}-{}", STORE_DIR, to_base32(&hash), name)
+}
+
+/// Nix's makeFixedOutputPath, method = "r:" for recursive (NAR) hashes. Only recursive sha256
+/// paths can have references, appended to their kind, e.g. ":/nix/store/...-glibc:self"
+pub fn make_fixed_output_path(method: &str, algo: &str, hash: &[u8], references: &str, name: &str)
+        -> Option<String> {
+    if method == "r:" && algo == "sha256" {
+        Some(make_store_path(&format!("source{}", references), hash, name))
+    } else if references.is_empty() {
+        let fingerprint = format!("fixed:out:{}{}:{}:", method, algo, to_hex(hash));
+        Some(make_store_path("output:out", &sha256(fingerprint.as_bytes()), name))
+    } else { None }
+}
+
+#[test]
+fn encode_hashes() {
+    let hash = sha256(b"");
+    assert_eq!(to_hex(&hash), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_eq!(to_base32(&hash), "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73");
+    assert_eq!(to_base32(&[0xff]), "7z");
+    assert_eq!(from_base32(&to_base32(&hash)), Some(hash.to_vec()));
+    assert_eq!(from_base32("7z"), Some(vec![0xff]));
+    assert_eq!(from_base32("8z"), None);
+    assert_eq!(from_base32("e0"), None);
+    assert_eq!(from_hex(&to_hex(&hash)), Some(hash.to_vec()));
+    assert_eq!(from_hex("0g"), None);
+    assert_eq!(compress_hash(&[1, 2, 4], 2), vec![5, 2]);
+}
@@ -0,0 +1,67 @@
+use serde_derive::Deserialize;
+use log::warn;
+
+use crate::Error;
+
+/// A path to check, with the attribute it was evaluated from, if known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedInput {
+    /// A derivation or output path, possibly selecting outputs like hello.drv^out
+    pub path: String,
+    pub attr: Option<String>
+}
+
+// A line of nix-eval-jobs output, which has more fields we don't need
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EvalJob {
+    attr: Option<String>,
+    drv_path: Option<String>,
+    error: Option<String>
+}
+
+/// Reads one path per line, or the JSON lines nix-eval-jobs prints, skipping jobs that failed to evaluate
+pub fn read_input_list(text: &str) -> Result<Vec<ListedInput>, Error> {
+    let mut inputs = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue }
+
+        if !line.starts_with('{') {
+            inputs.push(ListedInput { path: line.to_owned(), attr: None });
+            continue
+        }
+
+        let job: EvalJob = serde_json::from_str(line)
+            .map_err(|e| Error::ParseInputList(format!("line {}: {}", number + 1, e)))?;
+        match (job.drv_path, job.error) {
+            (Some(path), _) => inputs.push(ListedInput { path, attr: job.attr }),
+            (None, error) => warn!("skipping {}: {}",
+                                   job.attr.as_deref().unwrap_or("job"),
+                                   error.as_deref().unwrap_or("no drvPath"))
+        }
+    }
+    Ok(inputs)
+}
+
+#[test]
+fn read_input_lists() {
+    let plain = "/nix/store/ki3gq0ylxrl54fh4bnd1q1ar0ls4bdi2-hello-2.10.drv\n\n\
+                 # comment\n  /nix/store/cif7s5k57iwcxwgcv01myyiypw1skz99-xz-5.2.4.drv^out,bin  \n";
+    assert_eq!(read_input_list(plain).unwrap(), vec![
+        ListedInput { path: String::from("/nix/store/ki3gq0ylxrl54fh4bnd1q1ar0ls4bdi2-hello-2.10.drv"), attr: None },
+        ListedInput { path: String::from("/nix/store/cif7s5k57iwcxwgcv01myyiypw1skz99-xz-5.2.4.drv^out,bin"), attr: None }
+    ]);
+
+    let jobs = concat!(
+        r#"{"attr":"hello","attrPath":["hello"],"drvPath":"/nix/store/ki3gq0ylxrl54fh4bnd1q1ar0ls4bdi2-hello-2.10.drv","#,
+        r#""name":"hello-2.10","outputs":{"out":"/nix/store/rgmc4d3spji36n2l1sicm80yq79dpcc2-hello-2.10"},"system":"x86_64-linux"}"#, "\n",
+        r#"{"attr":"broken","attrPath":["broken"],"error":"error: Package is marked as broken"}"#, "\n"
+    );
+    assert_eq!(read_input_list(jobs).unwrap(), vec![ListedInput {
+        path: String::from("/nix/store/ki3gq0ylxrl54fh4bnd1q1ar0ls4bdi2-hello-2.10.drv"),
+        attr: Some(String::from("hello"))
+    }]);
+
+    assert!(matches!(read_input_list("{\"attr\": "), Err(Error::ParseInputList(_))));
+}
@@ -1,28 +1,44 @@
+pub mod attribution;
+pub mod binary_cache;
 pub mod derivation;
+pub mod drv_source;
+pub mod error;
+pub mod gc_roots;
+pub mod hash;
+pub mod input_list;
+pub mod local_store;
+pub mod nar;
 pub mod narinfo;
+pub mod narinfo_cache;
+pub mod plan;
+pub mod realisation;
+pub mod signature;
+pub mod verify;
 
 use std::{
     str,
+    borrow::Cow,
     convert::TryInto,
     path::Path,
     collections::{
         hash_map::Entry::*,
         HashMap, HashSet
-    },
-    time::Duration
+    }
 };
 
 use futures::prelude::*;
-use tokio::timer::delay_for;
-use reqwest::{ Client, StatusCode };
 
-use url::Url;
 use serde_derive::Serialize;
 use log::{ error, warn, debug, trace };
 
-use crate::{ derivation::*, narinfo::* };
+pub use crate::error::Error;
+use crate::{
+    attribution::*, binary_cache::*, derivation::*, drv_source::*, local_store::*,
+    narinfo::*, narinfo_cache::*, realisation::*, signature::*
+};
 
 const NIX_HASH_LENGTH: usize = 32;
+pub const STORE_DIR: &str = "/nix/store";
 
 // Nix store hashes are the first 160 bits of a sha256 hash, base32 encoded.
 // That base32 representation could be decoded into a [u32; 5], but then
@@ -36,43 +52,55 @@ const NIX_HASH_LENGTH: usize = 32;
 #[derive(Debug, Hash, Copy, Clone, PartialEq, Eq)]
 pub struct StoreHash([u8; NIX_HASH_LENGTH]);
 impl StoreHash {
-    pub fn split(name: &str) -> (Self, &str) {
-        let (hash, rest) = name.split_at(NIX_HASH_LENGTH);
-        (StoreHash::from_name(hash), &rest[1..])
+    pub fn split(name: &str) -> Result<(Self, &str), Error> {
+        let hash = StoreHash::from_name(name)?;
+        match name[NIX_HASH_LENGTH..].strip_prefix('-') {
+            Some(rest) => Ok((hash, rest)),
+            None => Err(Error::InvalidStorePath(name.to_owned()))
+        }
     }
 
-    pub fn split_path<P: AsRef<Path>>(path: P) -> (Self, String) {
-        let name = path.as_ref()
-            .file_name().expect("Not a file")
-            .to_str().expect("Invalid filename");
-        let (hash, name) = StoreHash::split(name);
-        (hash, name.to_string())
+    pub fn split_path<P: AsRef<Path>>(path: P) -> Result<(Self, String), Error> {
+        let (hash, name) = StoreHash::split(file_name(path.as_ref())?)?;
+        Ok((hash, name.to_string()))
     }
 
     /// e.g. rgmc4d3spji36n2l1sicm80yq79dpcc2-hello-2.10
-    pub fn from_name(name: &str) -> Self {
-        let hash = name.as_bytes()[..NIX_HASH_LENGTH]
-                       .try_into().expect("Wrong slice length");
-        StoreHash(hash)
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        // only ASCII, so that the remaining name can be split off and to_str can't fail
+        name.as_bytes().get(..NIX_HASH_LENGTH)
+            .filter(|hash| hash.is_ascii())
+            .and_then(|hash| hash.try_into().ok())
+            .map(StoreHash)
+            .ok_or_else(|| Error::InvalidStorePath(name.to_owned()))
     }
 
     /// e.g. /nix/store/rgmc4d3spji36n2l1sicm80yq79dpcc2-hello-2.10
-    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
-        let name = path.as_ref()
-            .file_name().expect("Not a file")
-            .to_str().expect("Invalid filename");
-        StoreHash::from_name(name)
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        StoreHash::from_name(file_name(path.as_ref())?)
     }
 
     pub fn to_str(&self) -> &str { str::from_utf8(&self.0).expect("Invalid UTF8") }
 }
 
+fn file_name(path: &Path) -> Result<&str, Error> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::InvalidStorePath(path.display().to_string()))
+}
+
 #[derive(Debug, Clone)]
 pub enum StoreItem {
     Drv(Drv),
     NarInfo(Box<NarInfo>),
+    // Available, but not signed by any trusted key, so Nix won't substitute it
+    Untrusted(Box<NarInfo>),
     Source(String),
-    Output(String, StoreHash)
+    Output(String, StoreHash),
+    // Derivation that couldn't be read, e.g. because it was garbage-collected
+    UnknownDrv(String),
+    // Output that is already valid in the local store
+    Present(String)
 }
 
 impl StoreItem {
@@ -90,150 +118,496 @@ impl StoreItem {
 }
 
 #[derive(Default)]
-pub struct StoreCache(HashMap<StoreHash, StoreItem>);
+pub struct StoreCache {
+    items: HashMap<StoreHash, StoreItem>,
+    // Outputs whose paths aren't recorded in a derivation we could read, like realised
+    // floating content-addressed outputs, by derivation and output name
+    discovered_outputs: HashMap<(StoreHash, String), StoreHash>,
+    // Derivers of outputs since upgraded to narinfos, whose Deriver might differ or be missing
+    output_derivers: HashMap<StoreHash, StoreHash>,
+    // Decoded __json of derivations with __structuredAttrs, so it's only parsed once
+    structured_attrs: HashMap<StoreHash, serde_json::Map<String, serde_json::Value>>
+}
+
 impl StoreCache {
-    pub fn entries(&self) -> &HashMap<StoreHash, StoreItem> { &self.0 }
-    pub fn get(&self, hash: &StoreHash) -> Option<&StoreItem> { self.0.get(hash) }
+    pub fn entries(&self) -> &HashMap<StoreHash, StoreItem> { &self.items }
+    pub fn get(&self, hash: &StoreHash) -> Option<&StoreItem> { self.items.get(hash) }
+
+    /// Reads input derivations from the local store
+    pub fn discover_build_time_closure(&mut self, hash: StoreHash, drv: &Drv) -> Result<(), Error> {
+        self.discover_build_time_closure_from(&FileSystem, hash, drv)
+    }
 
     // Condition: discover_build_time_closure is only called with matching hash and drv
     // Invariant: forall d in self: forall d' in build-closure(d): d' in self
-    pub fn discover_build_time_closure(&mut self, hash: StoreHash, drv: &Drv) {
-        if self.0.contains_key(&hash) { return }
-        trace!("registering derivation {}", drv.find_name());
-        self.0.insert(hash, StoreItem::Drv(drv.clone()));
-
-        for path in &drv.input_srcs {
-            let (input_src_hash, input_src_name) = StoreHash::split_path(&path);
-
+    pub fn discover_build_time_closure_from(&mut self, source: &dyn DrvSource, hash: StoreHash, drv: &Drv)
+            -> Result<(), Error> {
+        if self.items.contains_key(&hash) { return Ok(()) }
+
+        // validate everything before registering anything
+        let input_srcs = drv.input_srcs.iter()
+            .map(|path| StoreHash::split_path(path).map(|split| (path, split)))
+            .collect::<Result<Vec<_>, _>>()?;
+        // floating outputs are only registered once realised
+        let outputs = drv.outputs.iter()
+            .filter(|output| output.has_path())
+            .map(|DrvOutput { key, path, .. }| StoreHash::split_path(path).map(|split| (key, split)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(attrs) = drv.structured_attrs() { self.structured_attrs.insert(hash, attrs); }
+        self.items.insert(hash, StoreItem::Drv(drv.clone()));
+        trace!("registering derivation {}", self.path_of(hash));
+
+        for (path, (input_src_hash, input_src_name)) in input_srcs {
             trace!("registering source {}", path);
-            self.0.insert(input_src_hash, StoreItem::Source(input_src_name));
+            self.items.insert(input_src_hash, StoreItem::Source(input_src_name));
         }
 
-        for DrvOutput { key, path, .. } in &drv.outputs {
-            let (output_hash, output_name) = StoreHash::split_path(&path);
-
+        for (key, (output_hash, output_name)) in outputs {
             trace!("registering output {} of {} to {}", key, output_name, output_hash.to_str());
-            self.0.insert(output_hash, StoreItem::Output(output_name, hash));
+            self.items.insert(output_hash, StoreItem::Output(output_name, hash));
         }
 
         for InputDrv { path, .. } in &drv.input_drvs {
-            let input_drv_hash = StoreHash::from_path(path);
+            let input_drv_hash = StoreHash::from_path(path)?;
 
             // check cache to avoid unnecessary IO/parsing
-            let input_drv = self.0.get(&input_drv_hash)
-                                  .and_then(|item| item.clone().as_drv())
-                                  .unwrap_or_else(|| Drv::read_from(&path));
+            let input_drv = match self.items.get(&input_drv_hash) {
+                Some(StoreItem::Drv(input_drv)) => input_drv.clone(),
+                Some(StoreItem::UnknownDrv(_)) => continue,
+                _ => match source.read_drv(path) {
+                    Ok(input_drv) => input_drv,
+                    // e.g. garbage-collected, but its outputs may still be around
+                    Err(e @ Error::ReadDrv(..)) | Err(e @ Error::MissingDrv(_)) => {
+                        debug!("unable to inspect {}: {}", path, e);
+                        let (_, name) = StoreHash::split_path(path)?;
+                        self.items.insert(input_drv_hash, StoreItem::UnknownDrv(name));
+                        continue
+                    }
+                    Err(e) => return Err(e)
+                }
+            };
 
-            self.discover_build_time_closure(input_drv_hash, &input_drv);
+            self.discover_build_time_closure_from(source, input_drv_hash, &input_drv)?;
         }
+
+        Ok(())
     }
 
-    pub async fn fetch_narinfo(&mut self, cache_roots: &[Url], retries: u32, concurrency: u32) -> u64 {
-        let output_hashes: Vec<StoreHash> = self.0.iter()
-            .filter_map(|(k, v)| {
-                if let StoreItem::Output(_, _) = v { Some(*k) }
-                else { None }
-            })
+    /// Registers an output path given instead of a derivation, along with the build-time
+    /// closure of its deriver, if known. Without a readable deriver, only the References of
+    /// its narinfo can be followed.
+    pub fn discover_output_from(&mut self, source: &dyn DrvSource, path: &str, deriver: Option<&str>)
+            -> Result<StoreHash, Error> {
+        let (hash, name) = StoreHash::split_path(path)?;
+        let deriver = match deriver {
+            Some(deriver) => deriver,
+            None => { debug!("no deriver known for {}", path); return Ok(hash) }
+        };
+        let deriver_hash = StoreHash::from_path(deriver)?;
+
+        match source.read_drv(deriver) {
+            Ok(drv) => self.discover_build_time_closure_from(source, deriver_hash, &drv)?,
+            Err(e @ Error::ReadDrv(..)) | Err(e @ Error::MissingDrv(_)) => {
+                debug!("unable to inspect deriver of {}: {}", path, e);
+                let (_, drv_name) = StoreHash::split_path(deriver)?;
+                self.items.entry(deriver_hash).or_insert(StoreItem::UnknownDrv(drv_name));
+            }
+            Err(e) => return Err(e)
+        }
+
+        // e.g. a realised floating output isn't recorded in its derivation
+        self.items.entry(hash).or_insert(StoreItem::Output(name, deriver_hash));
+        Ok(hash)
+    }
+
+    pub fn mark_present(&mut self, local: &LocalStore) -> u64 {
+        let mut present = 0;
+        for (hash, item) in self.items.iter_mut() {
+            if let StoreItem::Output(name, _) = item {
+                if local.is_valid(*hash, name) {
+                    trace!("{}-{} is already present", hash.to_str(), name);
+                    *item = StoreItem::Present(name.clone());
+                    present += 1;
+                }
+            }
+        }
+        present
+    }
+
+    pub fn discovered_output(&self, drv_hash: StoreHash, key: &str) -> Option<StoreHash> {
+        self.discovered_outputs.get(&(drv_hash, key.to_owned())).copied()
+    }
+
+    // Outputs of derivations we couldn't read can still be found through narinfo Deriver fields
+    fn discover_output_of_unknown(&mut self, hash: StoreHash) {
+        let narinfo = match self.get(&hash) {
+            Some(StoreItem::NarInfo(narinfo)) | Some(StoreItem::Untrusted(narinfo)) => narinfo,
+            _ => return
+        };
+        let deriver_hash = match narinfo.deriver.as_ref().and_then(|deriver| StoreHash::from_name(deriver).ok()) {
+            Some(deriver_hash) => deriver_hash,
+            None => return
+        };
+        let drv_name = match self.get(&deriver_hash) {
+            Some(StoreItem::UnknownDrv(drv_name)) => drv_name.trim_end_matches(".drv"),
+            _ => return
+        };
+
+        // e.g. hello-2.10 for out, hello-2.10-man for man
+        let key = match StoreHash::split_path(&narinfo.store_path) {
+            Ok((_, name)) if name == drv_name => String::from("out"),
+            Ok((_, name)) => match name.strip_prefix(drv_name).and_then(|rest| rest.strip_prefix('-')) {
+                Some(key) => key.to_owned(),
+                None => return
+            },
+            Err(_) => return
+        };
+
+        trace!("discovered output {} of unknown derivation {}", key, drv_name);
+        self.discovered_outputs.insert((deriver_hash, key), hash);
+    }
+
+    /// Where output of the derivation drv_hash is, if recorded in it or realised in a cache
+    pub fn output_hash(&self, drv_hash: StoreHash, output: &DrvOutput) -> Option<StoreHash> {
+        if output.has_path() {
+            StoreHash::from_path(&output.path).ok()
+        } else {
+            self.discovered_output(drv_hash, &output.key)
+        }
+    }
+
+    /// Hashes of the given outputs of drv. Outputs whose path is only known after
+    /// building are represented by the derivation itself, which has to be built.
+    pub fn outputs_of<'a>(&self, drv_hash: StoreHash, drv: &Drv, keys: impl IntoIterator<Item = &'a str>)
+            -> Vec<StoreHash> {
+        let mut hashes: Vec<StoreHash> = keys.into_iter()
+            .filter_map(|key| drv.find_output(key))
+            .map(|output| self.output_hash(drv_hash, output).unwrap_or(drv_hash))
             .collect();
+        hashes.dedup();
+        hashes
+    }
+
+    /// Resolves floating content-addressed outputs through realisations in caches,
+    /// registering them as outputs of their derivation
+    pub async fn fetch_realisations(&mut self, caches: &[Box<dyn BinaryCache>], concurrency: u32) -> u64 {
+        let mut known = HashMap::new();
+        let mut wanted = Vec::new();
+        for (hash, item) in &self.items {
+            let drv = match item { StoreItem::Drv(drv) => drv, _ => continue };
+            let floating: Vec<&str> = drv.outputs.iter()
+                .filter(|output| output.kind() == OutputKind::Floating)
+                .map(|output| &output.key[..])
+                .collect();
+            if floating.is_empty() { continue }
+
+            match drv.hash_modulo(self, &mut known, true) {
+                Ok(drv_hash) => wanted.extend(floating.into_iter()
+                    .map(|key| (*hash, key.to_owned(), Realisation::id(&drv_hash, key)))),
+                Err(e) => warn!("unable to resolve outputs of {}: {}", self.path_of(*hash), e)
+            }
+        }
 
-        debug!("checking {} outputs", output_hashes.len());
+        debug!("resolving {} floating outputs", wanted.len());
 
-        async fn fetch_narinfo(c: &Client, url: Url) -> Result<Option<NarInfo>, reqwest::Error> {
-            let response = c.get(url).send().await?;
-            if response.status() == StatusCode::NOT_FOUND {
-                return Ok(None)
+        let realisations: Vec<_> = stream::iter(wanted)
+            .map(|(hash, key, id)| async move { (hash, key, fetch_first_realisation(caches, &id).await) })
+            .buffer_unordered(concurrency as usize)
+            .collect().await;
+
+        let mut resolved = 0;
+        for (drv_hash, key, realisation) in realisations {
+            let realisation = match realisation { Some(realisation) => realisation, None => continue };
+            match StoreHash::split_path(&realisation.out_path) {
+                Ok((output_hash, output_name)) => {
+                    trace!("output {} of {} is realised as {}", key, drv_hash.to_str(), realisation.out_path);
+                    self.items.entry(output_hash).or_insert(StoreItem::Output(output_name, drv_hash));
+                    self.discovered_outputs.insert((drv_hash, key), output_hash);
+                    resolved += 1;
+                }
+                Err(e) => warn!("ignoring realisation {}: {}", realisation.id, e)
             }
+        }
+
+        resolved
+    }
+
+    /// Which derivation in this cache produces hash, if any, even if it couldn't be read
+    pub fn deriver_of(&self, hash: StoreHash) -> Option<StoreHash> {
+        if let Some(deriver) = self.output_derivers.get(&hash) { return Some(*deriver) }
+        let deriver = match self.get(&hash)? {
+            StoreItem::Output(_, deriver_hash) => *deriver_hash,
+            StoreItem::NarInfo(narinfo) | StoreItem::Untrusted(narinfo) =>
+                StoreHash::from_name(narinfo.deriver.as_ref()?).ok()?,
+            _ => return None
+        };
+
+        match self.get(&deriver) {
+            Some(StoreItem::Drv(_)) | Some(StoreItem::UnknownDrv(_)) => Some(deriver),
+            _ => None
+        }
+    }
 
-            let bytes = response.bytes().await?;
-            Ok(NarInfo::from(&bytes[..]))
+    /// Attributes of a registered derivation, with __json only decoded once
+    pub fn drv_attrs(&self, hash: StoreHash) -> Option<Attrs<'_>> {
+        match (self.get(&hash)?, self.structured_attrs.get(&hash)) {
+            (StoreItem::Drv(_), Some(attrs)) => Some(Attrs::Structured(Cow::Borrowed(attrs))),
+            (StoreItem::Drv(drv), None) => Some(Attrs::Env(&drv.env)),
+            _ => None
         }
+    }
+
+    /// Whether Nix would fetch hash from a cache, instead of building it
+    pub fn is_substitutable(&self, hash: StoreHash) -> bool {
+        let deriver = self.deriver_of(hash).and_then(|deriver| self.drv_attrs(deriver));
+        matches!(self.get(&hash), Some(StoreItem::NarInfo(_))) &&
+            deriver.is_none_or(|attrs| attrs.allows_substitutes())
+    }
+
+    /// Best-effort store path of hash, only the hash if nothing else is known
+    pub fn path_of(&self, hash: StoreHash) -> String {
+        match self.get(&hash) {
+            Some(StoreItem::NarInfo(narinfo)) | Some(StoreItem::Untrusted(narinfo)) => narinfo.store_path.clone(),
+            Some(StoreItem::Drv(drv)) => {
+                let attrs = self.drv_attrs(hash).unwrap_or_else(|| drv.attrs());
+                format!("{}/{}-{}.drv", STORE_DIR, hash.to_str(), attrs.name())
+            }
+            Some(StoreItem::Source(name)) | Some(StoreItem::Output(name, _)) |
+            Some(StoreItem::Present(name)) | Some(StoreItem::UnknownDrv(name)) =>
+                format!("{}/{}-{}", STORE_DIR, hash.to_str(), name),
+            None => format!("{}/{}", STORE_DIR, hash.to_str())
+        }
+    }
+
+    /// What has to be available for hash to be usable, following narinfo references
+    /// where we have them, and falling back to the derivation graph where we don't
+    pub fn dependencies(&self, hash: StoreHash) -> Vec<StoreHash> {
+        match self.get(&hash) {
+            Some(StoreItem::NarInfo(narinfo)) | Some(StoreItem::Untrusted(narinfo)) =>
+                narinfo.references.iter()
+                    .filter_map(|name| StoreHash::from_name(name)
+                        .map_err(|e| warn!("ignoring reference of {}: {}", narinfo.store_path, e))
+                        .ok())
+                    .collect(),
 
-        async fn fetch_first_narinfo(c: &Client, cache_roots: &[Url], max_attempts: u32, hash: StoreHash)
-                -> Result<(StoreHash, Option<NarInfo>), reqwest::Error> {
-            'next_cache: for cache_root in cache_roots {
-                let url = cache_root.join(&format!("{}.narinfo", hash.to_str()))
-                                    .expect("Invalid URL join");
-                trace!("fetching {}", url);
-                let mut delay = 64;
-
-                for _ in 0..max_attempts {
-                    if let Ok(response) = fetch_narinfo(c, url.clone()).await {
-                        match response {
-                            Some(narinfo) => return Ok((hash, Some(narinfo))),
-                            None => continue 'next_cache
+            Some(StoreItem::Output(_, deriver_hash)) => vec![*deriver_hash],
+
+            Some(StoreItem::Drv(drv)) =>
+                drv.input_drvs.iter()
+                    .filter_map(|input| input.resolve(self)
+                        .map_err(|e| warn!("ignoring input of {}: {}", self.path_of(hash), e))
+                        .ok())
+                    .flatten()
+                    .collect(),
+
+            // The closure of a valid path is valid as well
+            Some(StoreItem::Present(_)) => Vec::new(),
+
+            _ => Vec::new()
+        }
+    }
+
+    /// Walks down from roots, fetching narinfos only for outputs that are actually reached.
+    /// Without trusted_keys, signatures aren't checked at all. With a matrix, every cache
+    /// is asked for every path, instead of stopping at the first that has it.
+    pub async fn fetch_narinfo(&mut self, roots: &[StoreHash], caches: &[Box<dyn BinaryCache>], concurrency: u32,
+                               narinfo_cache: Option<&NarInfoCache>, trusted_keys: Option<&[PublicKey]>,
+                               mut matrix: Option<&mut CacheMatrix>) -> u64 {
+        let query_all = matrix.is_some();
+        let transaction = narinfo_cache.and_then(|narinfo_cache|
+            narinfo_cache.transaction()
+                .map_err(|e| warn!("unable to write to narinfo cache: {}", e))
+                .ok());
+
+        let mut visited = HashSet::new();
+        let mut frontier = roots.to_vec();
+        let mut fetched = 0;
+
+        while !frontier.is_empty() {
+            let mut next = Vec::new();
+            let mut to_fetch = Vec::new();
+            for hash in frontier.drain(..) {
+                if !visited.insert(hash) { continue }
+                match self.get(&hash) {
+                    Some(StoreItem::Output(_, _)) | None => to_fetch.push(hash),
+                    Some(_) => next.extend(self.dependencies(hash))
+                }
+            }
+
+            debug!("checking {} outputs", to_fetch.len());
+
+            let narinfos: Vec<_> = stream::iter(to_fetch.iter().copied())
+                .map(|hash| fetch_first_narinfo(caches, narinfo_cache, trusted_keys, hash, query_all))
+                .buffer_unordered(concurrency as usize)
+                .collect().await;
+
+            // merge into self without overwriting
+            for (hash, item, hits) in narinfos {
+                if let Some(matrix) = matrix.as_deref_mut() { matrix.record(hash, hits) }
+                let item = match item { Some(item) => item, None => continue };
+                fetched += 1;
+                match self.items.entry(hash) {
+                    Vacant(e) =>       { e.insert(item); }
+                    Occupied(mut e) => match e.get() {
+                        // upgrade output to narinfo
+                        StoreItem::Output(_, deriver) => {
+                            self.output_derivers.insert(hash, *deriver);
+                            e.insert(item);
                         }
+                        duplicate => warn!("got duplicate at {:?}", duplicate)
                     }
+                }
+            }
 
-                    delay_for(Duration::from_millis(delay)).await;
-                    delay *= 2;
+            // missing outputs continue into their derivation
+            for hash in to_fetch {
+                self.discover_output_of_unknown(hash);
+                next.extend(self.dependencies(hash));
+
+                // cached, but built anyway, e.g. because it's untrusted, which needs the inputs of its derivation
+                if self.get(&hash).is_some() && !self.is_substitutable(hash) {
+                    next.extend(self.deriver_of(hash));
                 }
             }
+            frontier = next;
+        }
 
-            return Ok((hash, None))
+        if let Some(Err(e)) = transaction.map(|t| t.commit()) {
+            warn!("unable to write to narinfo cache: {}", e);
         }
 
-        let client = Client::new();
+        fetched
+    }
+}
 
-        let mut narinfos = stream::iter(output_hashes)
-            .map(|hash| fetch_first_narinfo(&client, cache_roots, retries, hash))
-            .buffer_unordered(concurrency as usize)
-            .filter_map(|res| match res {
-                Ok((_, None))    => future::ready(None),
-                Ok((h, Some(n))) => future::ready(Some((h, n))),
-                Err(e) => { error!("{}", e); future::ready(None) }
-            });
+async fn lookup_narinfo(cache: &dyn BinaryCache, narinfo_cache: Option<&NarInfoCache>, hash: StoreHash)
+        -> Option<NarInfo> {
+    let narinfo_cache = narinfo_cache.filter(|_| !cache.is_local());
+
+    let cached = narinfo_cache.and_then(|narinfo_cache|
+        narinfo_cache.lookup(cache.url(), hash)
+            .unwrap_or_else(|e| { warn!("narinfo cache lookup failed: {}", e); None }));
+    match cached {
+        Some(CachedNarInfo::Present(narinfo)) => return Some(*narinfo),
+        Some(CachedNarInfo::Absent) => return None,
+        None => ()
+    }
 
-        let mut fetched = 0;
-        // merge into self without overwriting
-        while let Some((hash, narinfo)) = narinfos.next().await {
-            fetched += 1;
-            match self.0.entry(hash) {
-                Vacant(e) =>       { e.insert(StoreItem::NarInfo(Box::new(narinfo))); }
-                Occupied(mut e) => match e.get() {
-                    // upgrade output to narinfo
-                    StoreItem::Output(_, _) => { e.insert(StoreItem::NarInfo(Box::new(narinfo))); }
-                    duplicate => warn!("got duplicate at {:?}", duplicate)
+    match cache.narinfo(hash).await {
+        Ok(response) => {
+            if let Some(narinfo_cache) = narinfo_cache {
+                if let Err(e) = narinfo_cache.insert(cache.url(), hash, response.as_ref()) {
+                    warn!("unable to cache narinfo: {}", e);
                 }
             }
+            response
         }
+        Err(e) => { error!("{}", e); None }
+    }
+}
 
-        fetched
+/// Which derivation produced hash according to the first cache that has it, as a store path
+pub async fn find_deriver(caches: &[Box<dyn BinaryCache>], narinfo_cache: Option<&NarInfoCache>, hash: StoreHash)
+        -> Option<String> {
+    // Deriver isn't covered by signatures anyway
+    match fetch_first_narinfo(caches, narinfo_cache, None, hash, false).await {
+        (_, Some(StoreItem::NarInfo(narinfo)), _) => narinfo.deriver
+            .filter(|deriver| deriver.ends_with(".drv"))
+            .map(|deriver| format!("{}/{}", STORE_DIR, deriver)),
+        _ => None
     }
 }
 
+async fn fetch_first_narinfo(caches: &[Box<dyn BinaryCache>], narinfo_cache: Option<&NarInfoCache>,
+                             trusted_keys: Option<&[PublicKey]>, hash: StoreHash, query_all: bool)
+        -> (StoreHash, Option<StoreItem>, Vec<CacheHit>) {
+    let (mut trusted, mut untrusted, mut hits) = (None, None, Vec::new());
+    for (index, cache) in caches.iter().enumerate() {
+        if trusted.is_some() && !query_all { break }
+        if let Some(narinfo) = lookup_narinfo(&**cache, narinfo_cache, hash).await {
+            let is_trusted = match trusted_keys { Some(keys) => narinfo.is_trusted(keys), None => true };
+            hits.push(CacheHit {
+                cache: index,
+                file_size: narinfo.file_size,
+                nar_size: narinfo.nar_size,
+                trusted: is_trusted
+            });
+
+            // Nix ignores untrusted substitutes and tries the next cache
+            if is_trusted { trusted.get_or_insert(narinfo); } else { untrusted.get_or_insert(narinfo); }
+        }
+    }
+
+    let item = match (trusted, untrusted) {
+        (Some(narinfo), _) => Some(StoreItem::NarInfo(Box::new(narinfo))),
+        (None, Some(narinfo)) => Some(StoreItem::Untrusted(Box::new(narinfo))),
+        (None, None) => None
+    };
+    (hash, item, hits)
+}
+
+async fn fetch_first_realisation(caches: &[Box<dyn BinaryCache>], id: &str) -> Option<Realisation> {
+    for cache in caches {
+        match cache.realisation(id).await {
+            Ok(Some(realisation)) => return Some(realisation),
+            Ok(None) => (),
+            Err(e) => error!("{}", e)
+        }
+    }
+    None
+}
+
 #[derive(Default, Debug, Serialize)]
 pub struct CoverageStatistics {
     pub total: u64,
+    pub present: u64,
     pub found: u64,
     pub file_size: u64,
     pub nar_size: u64,
-    pub missing: Vec<String>
+    pub untrusted: Vec<String>,
+    pub missing: Vec<String>,
+    /// Missing sources, which only have to be downloaded from upstream
+    pub upstream: Vec<String>,
+    /// Derivations Nix won't substitute, even if cached, like writeText
+    pub local: Vec<String>,
+    /// Derivations that couldn't be read, so it's unknown what they'd need to build
+    pub uninspectable: Vec<String>
+}
+
+impl CoverageStatistics {
+    /// Outputs that won't have to be built, either because they're present or substitutable
+    pub fn available(&self) -> u64 { self.present + self.found }
+
+    pub fn percentage(&self) -> f32 { 100. * self.available() as f32 / self.total as f32 }
+
+    /// Only counts substitutable outputs, whatever happens to be present locally
+    pub fn substitutable_percentage(&self) -> f32 { 100. * self.found as f32 / self.total as f32 }
+
+    /// Counts derivations Nix won't substitute as missing after all
+    pub fn include_local_builds(&mut self) {
+        self.missing.append(&mut self.local);
+        self.missing.sort();
+    }
 }
 
 pub struct Closure(HashSet<StoreHash>);
 impl Closure {
     pub fn empty() -> Self { Closure(HashSet::default()) }
-    
+
+    /// Runtime closure of all roots together, with shared dependencies only counted once
+    pub fn of(roots: &[StoreHash], store: &StoreCache) -> Self {
+        let mut closure = Closure::empty();
+        for root in roots { closure.add_runtime_closure_of(*root, store) }
+        closure
+    }
+
     pub fn add_runtime_closure_of(&mut self, hash: StoreHash, store: &StoreCache) {
         if self.0.contains(&hash) { return }
         self.0.insert(hash);
 
-        match store.get(&hash) {
-            Some(StoreItem::NarInfo(narinfo)) =>
-                narinfo.references.iter()
-                    .map(|name| StoreHash::from_name(name))
-                    .for_each(|hash| self.add_runtime_closure_of(hash, store)),
-
-            Some(StoreItem::Output(_, deriver_hash)) =>
-                self.add_runtime_closure_of(*deriver_hash, store),
-
-            Some(StoreItem::Drv(drv)) =>
-                drv.input_drvs.iter()
-                    .flat_map(|input| input.resolve(store))
-                    .map(StoreHash::from_path)
-                    .for_each(|hash| self.add_runtime_closure_of(hash, store)),
-
-            _ => ()
+        for dependency in store.dependencies(hash) {
+            self.add_runtime_closure_of(dependency, store);
         }
     }
 
@@ -242,18 +616,45 @@ impl Closure {
 
         fn process(stats: &mut CoverageStatistics, store: &StoreCache, hash: StoreHash) {
             match store.get(&hash) {
+                // cached, but Nix builds it anyway
+                Some(StoreItem::NarInfo(_)) if !store.is_substitutable(hash) => {
+                    if let Some(deriver) = store.deriver_of(hash) { process(stats, store, deriver) }
+                }
                 Some(StoreItem::NarInfo(narinfo)) => {
                     stats.found += 1;
-                    stats.file_size += narinfo.file_size;
+                    // unknown when the cache compresses on the fly, like nix-serve
+                    stats.file_size += narinfo.file_size.unwrap_or(0);
                     stats.nar_size += narinfo.nar_size;
                 }
                 Some(StoreItem::Drv(drv)) => {
-                    stats.missing.push(drv.find_name());
+                    let attrs = store.drv_attrs(hash).unwrap_or_else(|| drv.attrs());
+                    if drv.is_upstream_fetch() {
+                        stats.upstream.push(attrs.name());
+                    } else if !attrs.allows_substitutes() {
+                        stats.local.push(attrs.name());
+                    } else {
+                        stats.missing.push(attrs.name());
+                    }
+                }
+                Some(StoreItem::Present(_name)) => {
+                    stats.present += 1;
+                }
+                Some(StoreItem::Untrusted(narinfo)) => {
+                    stats.untrusted.push(StoreHash::split_path(&narinfo.store_path)
+                        .map(|(_, name)| name)
+                        .unwrap_or_else(|_| narinfo.store_path.clone()));
+                }
+                Some(StoreItem::UnknownDrv(name)) => {
+                    stats.uninspectable.push(name.clone());
                 }
                 // Sources don't have to be built
                 Some(StoreItem::Source(_name)) => {}
+                // e.g. from a bogus realisation, there is nothing to build it from
+                Some(StoreItem::Output(name, deriver_hash)) if *deriver_hash == hash => {
+                    warn!("output {} claims to derive itself", store.path_of(hash));
+                    stats.missing.push(name.clone());
+                }
                 Some(StoreItem::Output(_name, deriver_hash)) => {
-                    assert!(&hash != deriver_hash, "output can't derive itself: {}", hash.to_str());
                     process(stats, store, *deriver_hash)
                 },
                 None => {
@@ -267,9 +668,245 @@ impl Closure {
 
         stats.missing.sort();
         stats.missing.dedup();
+        stats.upstream.sort();
+        stats.upstream.dedup();
+        stats.local.sort();
+        stats.local.dedup();
+        stats.uninspectable.sort();
+        stats.uninspectable.dedup();
+        stats.untrusted.sort();
 
         stats
     }
 
     pub fn entries(&self) -> &HashSet<StoreHash> { &self.0 }
 }
+
+#[test]
+fn split_store_path() {
+    let (hash, name) = StoreHash::split_path("/nix/store/rgmc4d3spji36n2l1sicm80yq79dpcc2-hello-2.10").unwrap();
+    assert_eq!(hash.to_str(), "rgmc4d3spji36n2l1sicm80yq79dpcc2");
+    assert_eq!(name, "hello-2.10");
+
+    assert!(StoreHash::from_name("rgmc4d3spji36n2l1").is_err());
+    assert!(StoreHash::split("rgmc4d3spji36n2l1sicm80yq79dpcc2").is_err());
+    assert!(StoreHash::split("rgmc4d3spji36n2l1sicm80yq79dpc\u{e4}-hello").is_err());
+    assert!(StoreHash::split_path("/").is_err());
+}
+
+#[test]
+fn fetch_from_file_cache() {
+    let cache_dir = std::env::temp_dir().join(format!("nix-weather-cache-{}", std::process::id()));
+    std::fs::create_dir_all(&cache_dir).unwrap();
+
+    let narinfo = NarInfo::parse(include_bytes!("../assets/dejagnu.narinfo")).unwrap();
+    let (hash, name) = StoreHash::split_path(&narinfo.store_path).unwrap();
+    let (missing, _) = StoreHash::split_path("/nix/store/00000000000000000000000000000000-missing").unwrap();
+    let deriver = StoreHash::from_name(narinfo.deriver.as_ref().unwrap()).unwrap();
+    std::fs::write(cache_dir.join(format!("{}.narinfo", hash.to_str())), narinfo.to_string()).unwrap();
+
+    let mut store = StoreCache::default();
+    store.items.insert(hash, StoreItem::Output(name, deriver));
+    store.items.insert(missing, StoreItem::Output(String::from("missing"), deriver));
+
+    let url = url::Url::from_directory_path(&cache_dir).unwrap();
+    let caches = [binary_cache::from_url(url, std::num::NonZeroU32::new(1).unwrap()).unwrap()];
+    let fetched = futures::executor::block_on(store.fetch_narinfo(&[hash, missing], &caches, 1, None, None, None));
+    assert_eq!(fetched, 1);
+    assert!(matches!(store.get(&hash), Some(StoreItem::NarInfo(found)) if **found == narinfo));
+    assert!(matches!(store.get(&missing), Some(StoreItem::Output(_, _))));
+
+    std::fs::remove_dir_all(&cache_dir).unwrap();
+}
+
+#[test]
+fn fetch_only_reachable_narinfo() {
+    let cache = test_cache("test");
+    let root = "11111111111111111111111111111111-root";
+    let dependency = "22222222222222222222222222222222-dependency";
+    let unrelated = "33333333333333333333333333333333-unrelated";
+    cache.insert(test_narinfo(root, &[root, dependency])).unwrap();
+    cache.insert(test_narinfo(dependency, &[])).unwrap();
+    cache.insert(test_narinfo(unrelated, &[])).unwrap();
+
+    let deriver = StoreHash::from_name("00000000000000000000000000000000").unwrap();
+    let mut store = StoreCache::default();
+    for name in &[root, unrelated] {
+        let (hash, name) = StoreHash::split(name).unwrap();
+        store.items.insert(hash, StoreItem::Output(name.to_owned(), deriver));
+    }
+
+    let root = StoreHash::from_name(root).unwrap();
+    let caches: [Box<dyn BinaryCache>; 1] = [Box::new(cache)];
+    let fetched = futures::executor::block_on(store.fetch_narinfo(&[root], &caches, 4, None, None, None));
+
+    assert_eq!(fetched, 2);
+    assert!(matches!(store.get(&StoreHash::from_name(dependency).unwrap()), Some(StoreItem::NarInfo(_))));
+    assert!(matches!(store.get(&StoreHash::from_name(unrelated).unwrap()), Some(StoreItem::Output(_, _))));
+}
+
+#[test]
+fn resolve_floating_outputs() {
+    let floating = |name: &str| {
+        let mut drv = test_drv(&format!("00000000000000000000000000000000-{}", name), &[]);
+        drv.outputs[0].path.clear();
+        drv.outputs[0].hash_algo = String::from("r:sha256");
+        drv
+    };
+    let (realised, unrealised) = (floating("realised"), floating("unrealised"));
+    let realised_hash = StoreHash::from_name("11111111111111111111111111111111").unwrap();
+    let unrealised_hash = StoreHash::from_name("22222222222222222222222222222222").unwrap();
+
+    let mut store = StoreCache::default();
+    store.discover_build_time_closure(realised_hash, &realised).unwrap();
+    store.discover_build_time_closure(unrealised_hash, &unrealised).unwrap();
+    assert_eq!(store.entries().len(), 2);
+
+    let cache = test_cache("test");
+    let drv_hash = realised.hash_modulo(&store, &mut HashMap::new(), true).unwrap();
+    cache.insert_realisation(Realisation {
+        id: Realisation::id(&drv_hash, "out"),
+        out_path: String::from("33333333333333333333333333333333-realised"),
+        signatures: Vec::new(),
+        dependent_realisations: Default::default()
+    });
+
+    let caches: [Box<dyn BinaryCache>; 1] = [Box::new(cache)];
+    assert_eq!(futures::executor::block_on(store.fetch_realisations(&caches, 4)), 1);
+
+    let output_hash = StoreHash::from_name("33333333333333333333333333333333").unwrap();
+    assert_eq!(store.outputs_of(realised_hash, &realised, vec!["out"]), vec![output_hash]);
+    assert_eq!(store.deriver_of(output_hash), Some(realised_hash));
+    // has to be built to know where it ends up
+    assert_eq!(store.outputs_of(unrealised_hash, &unrealised, vec!["out"]), vec![unrealised_hash]);
+}
+
+#[test]
+fn discover_outputs_of_unknown_drvs() {
+    let gone = "/nix/store/cccccccccccccccccccccccccccccccc-gone-1.0.drv";
+    let mut drv = test_drv("11111111111111111111111111111111-a", &[&gone["/nix/store/".len()..]]);
+    drv.input_drvs[0].outputs.push(String::from("man"));
+    let drv_hash = StoreHash::from_name("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap();
+    let gone_hash = StoreHash::from_path(gone).unwrap();
+
+    let mut store = StoreCache::default();
+    store.discover_build_time_closure_from(&DrvMap::default(), drv_hash, &drv).unwrap();
+    assert!(matches!(store.get(&gone_hash), Some(StoreItem::UnknownDrv(name)) if name == "gone-1.0.drv"));
+
+    let narinfo = |name: &str, references: &[&str]| NarInfo {
+        deriver: Some(String::from("cccccccccccccccccccccccccccccccc-gone-1.0.drv")),
+        ..test_narinfo(name, references)
+    };
+    // a itself isn't cached, but something else refers to the man output of gone
+    let cache = test_cache("test");
+    cache.insert(narinfo("22222222222222222222222222222222-other", &["33333333333333333333333333333333-gone-1.0-man"])).unwrap();
+    cache.insert(narinfo("33333333333333333333333333333333-gone-1.0-man", &[])).unwrap();
+
+    let roots = [StoreHash::from_name("11111111111111111111111111111111").unwrap(),
+                 StoreHash::from_name("22222222222222222222222222222222").unwrap()];
+    store.items.insert(roots[0], StoreItem::Output(String::from("a"), drv_hash));
+    let caches: [Box<dyn BinaryCache>; 1] = [Box::new(cache)];
+    futures::executor::block_on(store.fetch_narinfo(&roots, &caches, 4, None, None, None));
+
+    let man = StoreHash::from_name("33333333333333333333333333333333").unwrap();
+    assert_eq!(store.discovered_output(gone_hash, "man"), Some(man));
+    assert_eq!(store.discovered_output(gone_hash, "out"), None);
+    assert_eq!(store.deriver_of(man), Some(gone_hash));
+
+    let mut closure = Closure::empty();
+    closure.add_runtime_closure_of(roots[0], &store);
+    let stats = closure.coverage_statistics(&store);
+    assert_eq!(stats.uninspectable, vec!["gone-1.0.drv"]);
+}
+
+#[test]
+fn discover_outputs_as_inputs() {
+    let mut drvs = DrvMap::default();
+    drvs.insert(String::from("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv"),
+                test_drv("11111111111111111111111111111111-a", &[]));
+
+    let mut store = StoreCache::default();
+    let a = store.discover_output_from(&drvs, "/nix/store/11111111111111111111111111111111-a",
+                                       Some("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv")).unwrap();
+    let a_drv = StoreHash::from_name("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap();
+    assert_eq!(store.deriver_of(a), Some(a_drv));
+
+    // deriver was garbage-collected
+    let b = store.discover_output_from(&drvs, "/nix/store/22222222222222222222222222222222-b",
+                                       Some("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv")).unwrap();
+    let b_drv = StoreHash::from_name("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb").unwrap();
+    assert!(matches!(store.get(&b_drv), Some(StoreItem::UnknownDrv(name)) if name == "b.drv"));
+    assert_eq!(store.deriver_of(b), Some(b_drv));
+
+    // only its narinfo can tell
+    let c = store.discover_output_from(&drvs, "/nix/store/33333333333333333333333333333333-c", None).unwrap();
+    assert!(store.get(&c).is_none());
+}
+
+#[test]
+fn check_outputs_as_inputs() {
+    let mut drvs = DrvMap::default();
+    drvs.insert(String::from("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv"),
+                test_drv("22222222222222222222222222222222-b", &[]));
+    drvs.insert(String::from("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv"),
+                test_drv("11111111111111111111111111111111-a", &["bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv"]));
+
+    let mut store = StoreCache::default();
+    let a = store.discover_output_from(&drvs, "/nix/store/11111111111111111111111111111111-a",
+                                       Some("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv")).unwrap();
+
+    // a is cached, but b, which it refers to, isn't
+    let cache = test_cache("test");
+    cache.insert(test_narinfo("11111111111111111111111111111111-a", &["22222222222222222222222222222222-b"])).unwrap();
+    let caches: [Box<dyn BinaryCache>; 1] = [Box::new(cache)];
+    futures::executor::block_on(store.fetch_narinfo(&[a], &caches, 4, None, None, None));
+
+    let mut closure = Closure::empty();
+    closure.add_runtime_closure_of(a, &store);
+    let stats = closure.coverage_statistics(&store);
+    assert_eq!((stats.found, stats.present, stats.missing), (1, 0, vec![String::from("b")]));
+}
+
+#[test]
+fn decode_structured_attrs_once() {
+    let mut drv = test_drv("11111111111111111111111111111111-a", &[]);
+    drv.env = vec![(String::from("__json"), String::from(r#"{"name":"a","allowSubstitutes":false}"#))];
+    let hash = StoreHash::from_name("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv").unwrap();
+    let mut store = StoreCache::default();
+    store.discover_build_time_closure_from(&DrvMap::default(), hash, &drv).unwrap();
+
+    let attrs = store.drv_attrs(hash).unwrap();
+    assert!(matches!(attrs, Attrs::Structured(Cow::Borrowed(_))));
+    assert!(!attrs.allows_substitutes());
+    assert_eq!(store.path_of(hash), "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv");
+    assert!(store.drv_attrs(StoreHash::from_name("11111111111111111111111111111111-a").unwrap()).is_none());
+}
+
+#[test]
+fn count_roots_separately() {
+    let (a, b, shared) = ("11111111111111111111111111111111-a", "22222222222222222222222222222222-b",
+                          "33333333333333333333333333333333-shared");
+    let mut store = StoreCache::default();
+    for narinfo in [test_narinfo(a, &[shared]), test_narinfo(b, &[shared]), test_narinfo(shared, &[])] {
+        let hash = StoreHash::from_path(&narinfo.store_path).unwrap();
+        store.items.insert(hash, StoreItem::NarInfo(Box::new(narinfo)));
+    }
+
+    let (a, b) = (StoreHash::from_name(a).unwrap(), StoreHash::from_name(b).unwrap());
+    let stats = Closure::of(&[a, b], &store).coverage_statistics(&store);
+    assert_eq!((stats.total, stats.file_size), (3, 30));
+    for root in [a, b] {
+        let stats = Closure::of(&[root], &store).coverage_statistics(&store);
+        assert_eq!((stats.total, stats.found, stats.file_size), (2, 2, 20));
+    }
+}
+
+#[test]
+fn skip_outputs_deriving_themselves() {
+    let hash = StoreHash::from_name("11111111111111111111111111111111").unwrap();
+    let mut store = StoreCache::default();
+    store.items.insert(hash, StoreItem::Output(String::from("loop"), hash));
+
+    let stats = Closure::of(&[hash], &store).coverage_statistics(&store);
+    assert_eq!((stats.total, stats.missing), (1, vec![String::from("loop")]));
+}
@@ -0,0 +1,107 @@
+use std::path::{ Path, PathBuf };
+
+use rusqlite::{ params, Connection, OpenFlags, OptionalExtension };
+use log::{ debug, warn };
+
+use crate::StoreHash;
+
+/// What is already valid in the local Nix store, and doesn't need to be fetched or built
+pub struct LocalStore {
+    store_dir: PathBuf,
+    db: Option<Connection>
+}
+
+impl LocalStore {
+    pub const DEFAULT_DB: &'static str = "/nix/var/nix/db/db.sqlite";
+
+    // The database is owned by the Nix daemon, so we might not be allowed to
+    // take part in its WAL. If even an immutable view fails, only look at the disk.
+    fn open_db(db_path: &Path) -> rusqlite::Result<Connection> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI;
+        let try_open = |uri: String| -> rusqlite::Result<Connection> {
+            let conn = Connection::open_with_flags(uri, flags)?;
+            conn.query_row("select count(*) from ValidPaths limit 1", params![], |_| Ok(()))?;
+            Ok(conn)
+        };
+
+        try_open(format!("file:{}", db_path.display()))
+            .or_else(|_| try_open(format!("file:{}?immutable=1", db_path.display())))
+    }
+
+    pub fn open<S: AsRef<Path>, D: AsRef<Path>>(store_dir: S, db_path: D) -> Self {
+        let db = LocalStore::open_db(db_path.as_ref())
+            .map_err(|e| warn!("unable to open Nix database {}, only checking {} instead: {}",
+                               db_path.as_ref().display(), store_dir.as_ref().display(), e))
+            .ok();
+        LocalStore { store_dir: store_dir.as_ref().to_owned(), db }
+    }
+
+    /// Only looks at the store directory, without consulting the Nix database
+    pub fn without_db<S: AsRef<Path>>(store_dir: S) -> Self {
+        LocalStore { store_dir: store_dir.as_ref().to_owned(), db: None }
+    }
+
+    pub fn path_of(&self, hash: StoreHash, name: &str) -> PathBuf {
+        self.store_dir.join(format!("{}-{}", hash.to_str(), name))
+    }
+
+    pub fn is_valid(&self, hash: StoreHash, name: &str) -> bool {
+        let path = self.path_of(hash, name);
+        match &self.db {
+            Some(db) => db.query_row("select 1 from ValidPaths where path = ?1",
+                                     params![path.to_string_lossy()], |_| Ok(()))
+                .optional()
+                .unwrap_or_else(|e| {
+                    debug!("unable to query Nix database for {}: {}", path.display(), e);
+                    None
+                })
+                .is_some(),
+            None => path.exists()
+        }
+    }
+
+    /// Which derivation built a valid path, as recorded in the Nix database
+    pub fn deriver_of(&self, hash: StoreHash, name: &str) -> Option<String> {
+        let path = self.path_of(hash, name);
+        self.db.as_ref()?
+            .query_row("select deriver from ValidPaths where path = ?1",
+                       params![path.to_string_lossy()], |row| row.get(0))
+            .optional()
+            .unwrap_or_else(|e| {
+                debug!("unable to query Nix database for {}: {}", path.display(), e);
+                None
+            })
+            .flatten()
+    }
+}
+
+#[test]
+fn local_validity() {
+    let store_dir = std::env::temp_dir().join(format!("nix-weather-store-{}", std::process::id()));
+    std::fs::create_dir_all(&store_dir).unwrap();
+
+    let on_disk = StoreHash::from_name("rgmc4d3spji36n2l1sicm80yq79dpcc2").unwrap();
+    let registered = StoreHash::from_name("cif7s5k57iwcxwgcv01myyiypw1skz99").unwrap();
+    std::fs::write(store_dir.join(format!("{}-hello-2.10", on_disk.to_str())), b"").unwrap();
+
+    let local = LocalStore::without_db(&store_dir);
+    assert!(local.is_valid(on_disk, "hello-2.10"));
+    assert!(!local.is_valid(registered, "stdenv-linux"));
+
+    let db_path = store_dir.join("db.sqlite");
+    let db = Connection::open(&db_path).unwrap();
+    db.execute_batch("create table ValidPaths (id integer primary key, path text unique not null, deriver text)").unwrap();
+    db.execute("insert into ValidPaths (path, deriver) values (?1, ?2)",
+               params![local.path_of(registered, "stdenv-linux").to_string_lossy(),
+                       "/nix/store/3q0nsrkmrxcphyi9bcd9rrfqzyq4hxr0-stdenv-linux.drv"]).unwrap();
+    drop(db);
+
+    let local = LocalStore::open(&store_dir, &db_path);
+    assert!(!local.is_valid(on_disk, "hello-2.10"));
+    assert!(local.is_valid(registered, "stdenv-linux"));
+    assert_eq!(local.deriver_of(registered, "stdenv-linux").as_deref(),
+               Some("/nix/store/3q0nsrkmrxcphyi9bcd9rrfqzyq4hxr0-stdenv-linux.drv"));
+    assert_eq!(local.deriver_of(on_disk, "hello-2.10"), None);
+
+    std::fs::remove_dir_all(&store_dir).unwrap();
+}
@@ -1,24 +1,62 @@
-use std::{ cmp, io, path::PathBuf, process };
+use std::{
+    cmp, collections::BTreeMap, fs, io::{ self, Read }, num::NonZeroU32, path::{ Path, PathBuf }, process,
+    time::Duration
+};
 
 use structopt::StructOpt;
+use serde_derive::Serialize;
 use log::*;
 use url::Url;
 use number_prefix::{ NumberPrefix, Standalone, Prefixed };
 
 use nix_weather::{
-    StoreHash, StoreCache,
+    Error,
+    StoreHash, StoreItem, StoreCache,
     Closure,
+    find_deriver,
     CoverageStatistics,
-    derivation::*
+    STORE_DIR,
+    attribution::{ Attribution, CacheMatrix },
+    binary_cache::{ self, BinaryCache },
+    derivation::OutputsSpec,
+    drv_source::{ DrvSource, DrvMap, FileSystem },
+    gc_roots::{ find_roots, STATE_DIR },
+    input_list,
+    local_store::LocalStore,
+    narinfo_cache::NarInfoCache,
+    plan::Plan,
+    signature::{ PublicKey, CACHE_NIXOS_ORG_KEY },
+    verify::Verification
 };
 
 #[derive(StructOpt, Debug)]
 struct Opt {
-    /// Which derivation to collect coverage statistics for (must reside in store)
+    /// Which derivations or built outputs to collect coverage statistics for (must reside in store).
+    /// Outputs are checked through their deriver, or only their narinfo if it is unknown, as if the store was wiped.
+    /// Select outputs of derivations like hello.drv^out,man, all by default. - reads a list from stdin
     #[structopt(name = "drv", parse(from_os_str))]
     input_derivations: Vec<PathBuf>,
 
-    /// Which HTTP(s) binary caches to query, tried in order of appearance
+    /// Read more inputs from a file, or stdin with -, one per line or as nix-eval-jobs JSON lines.
+    /// Inputs with attribute names get their own coverage
+    #[structopt(long, parse(from_os_str))]
+    from_file: Option<PathBuf>,
+
+    /// Read derivations from the output of nix derivation show -r instead of the store.
+    /// Without any drv, all derivations no other derivation depends on are checked
+    #[structopt(long, parse(from_os_str))]
+    drv_json: Option<PathBuf>,
+
+    /// Like --drv-json, but reading the output of nix-store --export, or a NAR of .drv files
+    #[structopt(long, parse(from_os_str), conflicts_with = "drv-json")]
+    drv_export: Option<PathBuf>,
+
+    /// Check everything kept alive by GC roots and profiles, as if the store was wiped.
+    /// Implies --no-local-store
+    #[structopt(long, conflicts_with_all = &["drv-json", "drv-export"])]
+    gc_roots: bool,
+
+    /// Which HTTP(s) or file:// binary caches to query, tried in order of appearance
     #[structopt(name = "cache", short, long, default_value = "https://cache.nixos.org")]
     cache_roots: Vec<Url>,
 
@@ -28,13 +66,70 @@ struct Opt {
 
     /// How often to try to fetch a .narinfo file
     #[structopt(short = "m", long, default_value = "3")]
-    narinfo_max_attempts: u32,
+    narinfo_max_attempts: NonZeroU32,
+
+    /// Where to persist .narinfo lookups [default: ~/.cache/nix-weather/narinfo-v2.sqlite]
+    #[structopt(long, parse(from_os_str))]
+    narinfo_cache: Option<PathBuf>,
+
+    /// Don't read or write the .narinfo cache
+    #[structopt(long)]
+    no_narinfo_cache: bool,
+
+    /// Forget all previously cached .narinfo lookups before starting
+    #[structopt(long)]
+    purge_narinfo_cache: bool,
+
+    /// How many seconds a cached .narinfo stays valid
+    #[structopt(long, default_value = "2592000")]
+    narinfo_ttl: u64,
+
+    /// How many seconds a cached absence of a .narinfo stays valid
+    #[structopt(long, default_value = "3600")]
+    narinfo_negative_ttl: u64,
+
+    /// Which keys a .narinfo has to be signed with to be substitutable
+    #[structopt(name = "trusted-public-key", long, default_value = CACHE_NIXOS_ORG_KEY)]
+    trusted_public_keys: Vec<PublicKey>,
+
+    /// Don't check .narinfo signatures, like Nix with require-sigs = false
+    #[structopt(long)]
+    no_require_sigs: bool,
+
+    /// Don't treat outputs already present in the local store as available.
+    /// Implied by --gc-roots and by outputs as inputs, which are always present
+    #[structopt(long)]
+    no_local_store: bool,
+
+    /// Which Nix database to consult for locally valid paths
+    #[structopt(long, parse(from_os_str), default_value = LocalStore::DEFAULT_DB)]
+    nix_db: PathBuf,
+
+    /// Ask every cache for every path, and report how much each of them contributes, by path with -v
+    #[structopt(long)]
+    all_caches: bool,
+
+    /// Count derivations Nix won't substitute, like writeText, as missing too
+    #[structopt(long)]
+    include_local_builds: bool,
+
+    /// Also list what exactly would be built and fetched, like nix-store --realise --dry-run
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Check that derivations and their recorded output paths are where Nix would put them
+    #[structopt(long)]
+    verify: bool,
+
+    /// Also report coverage of each input on its own, like for GC roots or attributes
+    #[structopt(long)]
+    per_root: bool,
 
     /// Output statistics in JSON
     #[structopt(long)]
     json: bool,
 
-    /// Output coverage percentage as exit code
+    /// Output the percentage of substitutable outputs as exit code, not counting present ones
     #[structopt(long, short)]
     percentage_as_exit: bool,
 
@@ -44,6 +139,53 @@ struct Opt {
     quiet: i32
 }
 
+#[derive(Serialize)]
+struct Report {
+    #[serde(flatten)]
+    stats: CoverageStatistics,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plan: Option<Plan>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attribution: Option<Attribution>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verification: Option<Verification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    roots: Option<Vec<RootCoverage>>
+}
+
+/// Coverage of a single input, like a GC root or an attribute of a jobset
+#[derive(Serialize)]
+struct RootCoverage {
+    root: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attr: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    links: Vec<PathBuf>,
+    #[serde(flatten)]
+    stats: CoverageStatistics
+}
+
+/// Something to check, with what is known about where it came from
+struct Installable {
+    path: String,
+    outputs: OutputsSpec,
+    /// Which attribute it was evaluated from, e.g. by nix-eval-jobs
+    attr: Option<String>,
+    /// GC roots keeping it alive
+    links: Vec<PathBuf>
+}
+
+impl Installable {
+    fn new(path: String) -> Self {
+        Installable { path, outputs: OutputsSpec::All, attr: None, links: Vec::new() }
+    }
+
+    fn parse(installable: &str, attr: Option<String>) -> Result<Self, Error> {
+        let (path, outputs) = OutputsSpec::split(installable)?;
+        Ok(Installable { outputs, attr, ..Installable::new(path.to_owned()) })
+    }
+}
+
 fn format_bytes(amount: u64) -> String {
     match NumberPrefix::binary(amount as f64) {
         Standalone(bytes) =>   format!("{} bytes", bytes),
@@ -54,22 +196,177 @@ fn format_bytes(amount: u64) -> String {
 fn print_statistics(stats: &CoverageStatistics) {
     println!("Fetched {} .narinfos", stats.total);
     println!("{}/{} ({:.2}%) outputs are available",
-             stats.found, stats.total,
-             100. * stats.found as f32 / stats.total as f32);
+             stats.available(), stats.total, stats.percentage());
+    println!("{} outputs are already present, {} are substitutable", stats.present, stats.found);
 
     println!("{} of Nix archives (compressed)", format_bytes(stats.file_size));
     println!("{} of Nix archives (uncompressed)", format_bytes(stats.nar_size));
 
-    let max_length = stats.missing.iter().map(String::len).max().unwrap_or(0);
+    if !stats.untrusted.is_empty() {
+        println!("The following outputs are cached, but not signed by a trusted key:");
+        print_names(&stats.untrusted);
+    }
+
     if !stats.missing.is_empty() {
         println!("The following derivations are missing and will have to be built locally:");
-        for names in stats.missing.chunks(3) {
-            for name in names { print!("{: <width$} ", name, width = max_length + 1); }
-            println!();
+        print_names(&stats.missing);
+    }
+
+    if !stats.uninspectable.is_empty() {
+        println!("The following {} derivations couldn't be inspected, e.g. because they were garbage-collected:",
+                 stats.uninspectable.len());
+        print_names(&stats.uninspectable);
+    }
+
+    if !stats.local.is_empty() {
+        println!("The following {} derivations will be built locally, because Nix won't substitute them:",
+                 stats.local.len());
+        print_names(&stats.local);
+    }
+
+    if !stats.upstream.is_empty() {
+        println!("The following {} sources are missing and will be fetched from upstream:", stats.upstream.len());
+        print_names(&stats.upstream);
+    }
+}
+
+fn print_plan(plan: &Plan) {
+    if !plan.will_build.is_empty() {
+        println!("these {} derivations will be built:", plan.will_build.len());
+        for path in &plan.will_build { println!("  {}", path); }
+    }
+
+    if !plan.will_fetch_upstream.is_empty() {
+        println!("these {} sources will be fetched from upstream:", plan.will_fetch_upstream.len());
+        for path in &plan.will_fetch_upstream { println!("  {}", path); }
+    }
+
+    if !plan.will_fetch.is_empty() {
+        println!("these {} paths will be fetched ({} download, {} unpacked):",
+                 plan.will_fetch.len(), format_bytes(plan.download_size), format_bytes(plan.nar_size));
+        for path in &plan.will_fetch { println!("  {}", path); }
+    }
+
+    if !plan.unknown.is_empty() {
+        println!("these {} paths can neither be fetched nor built:", plan.unknown.len());
+        for path in &plan.unknown { println!("  {}", path); }
+    }
+}
+
+fn print_attribution(attribution: &Attribution, verbose: bool) {
+    for cache in &attribution.caches {
+        println!("{}: {} paths found ({} compressed, {} uncompressed), {} would be fetched from here ({})",
+                 cache.url, cache.found, format_bytes(cache.file_size), format_bytes(cache.nar_size),
+                 cache.substituted, format_bytes(cache.download_size));
+        if cache.untrusted > 0 {
+            println!("  {} more paths are cached, but not signed by a trusted key", cache.untrusted);
+        }
+        if !cache.exclusive.is_empty() {
+            println!("  {} outputs are only available from here", cache.exclusive.len());
+        }
+    }
+
+    if !verbose { return }
+    for cache in &attribution.caches {
+        if !cache.exclusive.is_empty() {
+            println!("The following outputs are only available from {}:", cache.url);
+            print_names(&cache.exclusive);
+        }
+    }
+
+    for cache in &attribution.caches {
+        if cache.substituted == 0 { continue }
+        println!("these {} paths would be fetched from {}:", cache.substituted, cache.url);
+        for (path, _) in attribution.sources.iter().filter(|(_, url)| **url == cache.url) {
+            println!("  {}", path);
         }
     }
 }
 
+fn print_verification(verification: &Verification) {
+    println!("Verified output paths of {} derivations, {} couldn't be checked",
+             verification.checked, verification.skipped);
+    for mismatch in &verification.mismatched_drvs {
+        println!("{} doesn't match its contents, should be {}", mismatch.recorded, mismatch.computed);
+    }
+    for mismatch in &verification.mismatched {
+        println!("{}: output {} is {}, but should be {}",
+                 mismatch.drv, mismatch.output, mismatch.recorded, mismatch.computed);
+    }
+}
+
+fn print_roots(roots: &[RootCoverage], verbose: bool) {
+    if roots.is_empty() { return }
+    println!("Coverage per root:");
+    for root in roots {
+        println!("{:6.2}% {}/{} {} ({} compressed, {} uncompressed)",
+                 root.stats.percentage(), root.stats.available(), root.stats.total,
+                 root.attr.as_ref().unwrap_or(&root.root),
+                 format_bytes(root.stats.file_size), format_bytes(root.stats.nar_size));
+        for link in &root.links { println!("        kept alive by {}", link.display()); }
+        if root.stats.missing.is_empty() { continue }
+        println!("        {} derivations are missing", root.stats.missing.len());
+        if verbose { print_names(&root.stats.missing); }
+    }
+}
+
+fn print_names(names: &[String]) {
+    let max_length = names.iter().map(String::len).max().unwrap_or(0);
+    for names in names.chunks(3) {
+        for name in names { print!("{: <width$} ", name, width = max_length + 1); }
+        println!();
+    }
+}
+
+/// Registers the build-time closure of an input derivation, returning its hash
+fn discover_input(store: &mut StoreCache, source: &dyn DrvSource, path: &str) -> Result<StoreHash, Error> {
+    let input_hash = StoreHash::from_path(path)?;
+    let input_drv = source.read_drv(path)?;
+
+    store.discover_build_time_closure_from(source, input_hash, &input_drv)?;
+    Ok(input_hash)
+}
+
+/// Registers an input output path and what's known about its deriver, returning its hash
+async fn discover_output(store: &mut StoreCache, source: &dyn DrvSource, local: Option<&LocalStore>,
+                         caches: &[Box<dyn BinaryCache>], narinfo_cache: Option<&NarInfoCache>, path: &str)
+        -> Result<StoreHash, Error> {
+    let (hash, name) = StoreHash::split_path(path)?;
+    let deriver = match local.and_then(|local| local.deriver_of(hash, &name)) {
+        Some(deriver) => Some(deriver),
+        None => find_deriver(caches, narinfo_cache, hash).await
+    };
+
+    store.discover_output_from(source, path, deriver.as_deref())
+}
+
+fn coverage_of(outputs: &[StoreHash], store: &StoreCache, include_local_builds: bool)
+        -> (Closure, CoverageStatistics) {
+    let closure = Closure::of(outputs, store);
+
+    let mut stats = closure.coverage_statistics(store);
+    if include_local_builds { stats.include_local_builds() }
+    (closure, stats)
+}
+
+fn read_input_list(path: &Path) -> Result<Vec<Installable>, Error> {
+    let read_error = |e| Error::ReadInputList(path.to_owned(), e);
+    let text = if path == Path::new("-") {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).map_err(read_error)?;
+        text
+    } else { fs::read_to_string(path).map_err(read_error)? };
+
+    input_list::read_input_list(&text)?.into_iter()
+        .map(|input| Installable::parse(&input.path, input.attr))
+        .collect()
+}
+
+fn load_drvs(path: &Path, parse: fn(&[u8]) -> Result<DrvMap, Error>) -> Result<DrvMap, Error> {
+    let bytes = fs::read(path).map_err(|e| Error::ReadDrv(path.to_owned(), e))?;
+    parse(&bytes)
+}
+
 #[tokio::main]
 async fn main() {
     let opt = Opt::from_args();
@@ -81,48 +378,178 @@ async fn main() {
         .verbosity(verbosity as usize)
         .init().expect("Unable to init logging");
 
-    // Resolve symlinks, useful for ./result outputs
-    let input_paths = opt.input_derivations.into_iter()
-        .map(|path| path.canonicalize().expect("Unable to canonicalize input path"));
+    let dump = match (&opt.drv_json, &opt.drv_export) {
+        (Some(path), _) => Some(load_drvs(path, DrvMap::from_json)),
+        (None, Some(path)) => Some(load_drvs(path, DrvMap::from_archive)),
+        (None, None) => None
+    };
+
+    let gc_roots = if opt.gc_roots { find_roots(STATE_DIR) } else { BTreeMap::new() };
+    if opt.gc_roots { info!("found {} GC roots...", gc_roots.len()); }
+
+    let mut installables = Vec::new();
+    let lists = opt.input_derivations.iter().filter(|path| *path == Path::new("-")).chain(&opt.from_file);
+    for list in lists {
+        match read_input_list(list) {
+            Ok(listed) => installables.extend(listed),
+            Err(e) => { error!("{}", e); process::exit(1) }
+        }
+    }
+    for path in opt.input_derivations.iter().filter(|path| *path != Path::new("-")) {
+        match Installable::parse(&path.to_string_lossy(), None) {
+            Ok(installable) => installables.push(installable),
+            Err(e) => { error!("{}", e); process::exit(1) }
+        }
+    }
 
-    let inputs = input_paths.map(|path| (StoreHash::from_path(&path), Drv::read_from(path)));
+    let (source, installables): (Box<dyn DrvSource>, Vec<Installable>) = match dump {
+        Some(drvs) => {
+            let drvs = drvs.unwrap_or_else(|e| {
+                error!("{}", e);
+                process::exit(1)
+            });
+            let installables = if installables.is_empty() {
+                drvs.roots().into_iter().map(Installable::new).collect()
+            } else { installables };
+            (Box::new(drvs), installables)
+        }
+        None => (Box::new(FileSystem), installables.into_iter()
+            // Resolve symlinks, useful for ./result outputs
+            .filter_map(|installable| match Path::new(&installable.path).canonicalize() {
+                Ok(path) => Some(Installable { path: path.display().to_string(), ..installable }),
+                Err(e) => { error!("skipping {}: {}", installable.path, e); None }
+            })
+            .chain(gc_roots.into_iter().map(|(path, links)| Installable { links, ..Installable::new(path) }))
+            .collect())
+    };
 
-    let outputs: Vec<_> = 
-        inputs.flat_map(|(input_hash, input_drv)|
-            input_drv.outputs.clone().into_iter()
-                .map(move |out| (input_hash, input_drv.clone(), StoreHash::from_path(&out.path))))
+    debug!("using cache_roots: {:?}", &opt.cache_roots);
+    let caches: Vec<_> = opt.cache_roots.iter()
+        .filter_map(|root| binary_cache::from_url(root.clone(), opt.narinfo_max_attempts)
+            .map_err(|e| error!("skipping {}", e))
+            .ok())
         .collect();
 
+    let (positive_ttl, negative_ttl) = (Duration::from_secs(opt.narinfo_ttl),
+                                        Duration::from_secs(opt.narinfo_negative_ttl));
+    let narinfo_cache = if opt.no_narinfo_cache { None } else {
+        opt.narinfo_cache.clone().or_else(NarInfoCache::default_path)
+            .and_then(|path| {
+                if let Some(parent) = path.parent() {
+                    let _ = fs::create_dir_all(parent);
+                }
+                NarInfoCache::open(&path, positive_ttl, negative_ttl)
+                    .map_err(|e| warn!("unable to open narinfo cache {}: {}", path.display(), e))
+                    .ok()
+            })
+    };
+
+    if opt.purge_narinfo_cache {
+        if let Some(Err(e)) = narinfo_cache.as_ref().map(NarInfoCache::purge) {
+            warn!("unable to purge narinfo cache: {}", e);
+        }
+    }
+
+    // also knows derivers of output paths
+    let is_output = |path: &str| !path.ends_with(".drv");
+    let has_outputs = installables.iter().any(|input| is_output(&input.path));
+    // as if the store was wiped, otherwise a ./result would trivially be present
+    let no_local_store = opt.no_local_store || opt.gc_roots || has_outputs;
+    let local = if no_local_store && !has_outputs { None } else {
+        Some(LocalStore::open(STORE_DIR, &opt.nix_db))
+    };
+
     let mut store = StoreCache::default();
-    for (input_hash, input_drv, _output_hash) in outputs.iter() {
-        store.discover_build_time_closure(*input_hash, &input_drv);
+    let mut inputs = Vec::new();
+    for installable in &installables {
+        let path = &installable.path;
+        let discovered = if is_output(path) {
+            if installable.outputs != OutputsSpec::All { warn!("ignoring output selection of {}", path); }
+            discover_output(&mut store, &*source, local.as_ref(), &caches, narinfo_cache.as_ref(), path).await
+        } else {
+            discover_input(&mut store, &*source, path)
+        };
+        match discovered {
+            Ok(input_hash) => inputs.push((installable, input_hash)),
+            Err(e) => error!("skipping {}: {}", path, e)
+        }
     }
 
     info!("discovered {} store items...", store.entries().len());
 
-    debug!("using cache_roots: {:?}", &opt.cache_roots);
-    let fetched = store.fetch_narinfo(&opt.cache_roots, opt.narinfo_max_attempts, opt.narinfo_concurrency).await;
+    let realised = store.fetch_realisations(&caches, opt.narinfo_concurrency).await;
+    if realised > 0 { info!("resolved {} content-addressed outputs...", realised); }
+
+    let input_outputs: Vec<(&Installable, Vec<StoreHash>)> = inputs.iter()
+        .map(|&(installable, input_hash)| (installable, match store.get(&input_hash) {
+            Some(StoreItem::Drv(drv)) => {
+                let keys = installable.outputs.keys(drv);
+                for key in keys.iter().filter(|key| drv.find_output(key).is_none()) {
+                    warn!("{} has no output {}", installable.path, key);
+                }
+                store.outputs_of(input_hash, drv, keys)
+            }
+            // given as output path
+            _ => vec![input_hash]
+        }))
+        .collect();
+    let outputs: Vec<StoreHash> = input_outputs.iter()
+        .flat_map(|(_, outputs)| outputs.iter().copied())
+        .collect();
+
+    if let Some(local) = local.as_ref().filter(|_| !no_local_store) {
+        let present = store.mark_present(local);
+        info!("{} outputs are already present...", present);
+    }
+
+    let trusted_keys = if opt.no_require_sigs { None } else { Some(&opt.trusted_public_keys[..]) };
+    let mut matrix = if opt.all_caches { Some(CacheMatrix::default()) } else { None };
+    let fetched = store.fetch_narinfo(&outputs, &caches, opt.narinfo_concurrency,
+                                      narinfo_cache.as_ref(), trusted_keys, matrix.as_mut()).await;
 
     info!("fetched {} narinfo...", fetched);
 
     info!("building runtime closure...");
-    let mut runtime_closure = Closure::empty();
-    for (_input_hash, _input_drv, output_hash) in &outputs {
-        runtime_closure.add_runtime_closure_of(*output_hash, &store);
-    }
+    let (runtime_closure, stats) = coverage_of(&outputs, &store, opt.include_local_builds);
     info!("runtime closure is at most {} paths large", runtime_closure.entries().len());
 
-    let stats = runtime_closure.coverage_statistics(&store);
+    let has_roots = |installable: &&Installable|
+        opt.per_root || !installable.links.is_empty() || installable.attr.is_some();
+    let roots = if input_outputs.iter().any(|(installable, _)| has_roots(installable)) {
+        let mut roots: Vec<RootCoverage> = input_outputs.iter()
+            .filter(|(installable, _)| has_roots(installable))
+            .map(|(installable, outputs)| RootCoverage {
+                root: installable.path.clone(),
+                attr: installable.attr.clone(),
+                links: installable.links.clone(),
+                stats: coverage_of(outputs, &store, opt.include_local_builds).1
+            })
+            .collect();
+        // worst first
+        roots.sort_by(|a, b| a.stats.percentage().partial_cmp(&b.stats.percentage()).unwrap_or(cmp::Ordering::Equal));
+        Some(roots)
+    } else { None };
+
+    let report = Report {
+        stats,
+        plan: if opt.dry_run { Some(Plan::for_outputs(&outputs, &store)) } else { None },
+        attribution: matrix.map(|matrix| matrix.attribution(&runtime_closure, &store, &caches)),
+        verification: if opt.verify { Some(Verification::of(&store)) } else { None },
+        roots
+    };
 
     if opt.json {
-        serde_json::to_writer(&mut io::stdout().lock(), &stats)
+        serde_json::to_writer(&mut io::stdout().lock(), &report)
             .expect("Failed to write statistics");
     } else {
-        print_statistics(&stats);
+        print_statistics(&report.stats);
+        if let Some(plan) = &report.plan { print_plan(plan); }
+        if let Some(attribution) = &report.attribution { print_attribution(attribution, opt.verbose > 0); }
+        if let Some(verification) = &report.verification { print_verification(verification); }
+        if let Some(roots) = &report.roots { print_roots(roots, opt.verbose > 0); }
     }
 
     if opt.percentage_as_exit {
-        let percentage = 100. * stats.found as f32 / stats.total as f32;
-        process::exit(percentage as i32);
+        process::exit(report.stats.substitutable_percentage() as i32);
     }
 }
@@ -0,0 +1,255 @@
+use std::io::{ self, Read };
+
+const NAR_MAGIC: &str = "nix-archive-1";
+// "NIXE", follows every path in nix-store --export
+const EXPORT_MAGIC: u64 = 0x4558_494e;
+
+/// A file system object, as serialized by nix-store --dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NarEntry {
+    Regular { executable: bool, contents: Vec<u8> },
+    Symlink(String),
+    Directory(Vec<(String, NarEntry)>)
+}
+
+/// A store path in the output of nix-store --export
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedPath {
+    pub path: String,
+    pub nar: NarEntry,
+    pub references: Vec<String>,
+    pub deriver: Option<String>
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// Length-prefixed, padded with zeros to a multiple of 8 bytes
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut bytes = Vec::new();
+    // don't trust len enough to allocate it upfront
+    r.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated string"))
+    }
+
+    let mut padding = [0; 8];
+    let padding = &mut padding[..(8 - len % 8) as usize % 8];
+    r.read_exact(padding)?;
+    if padding.iter().any(|&byte| byte != 0) {
+        return Err(invalid(String::from("non-zero padding")))
+    }
+    Ok(bytes)
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(r)?).map_err(|e| invalid(e.to_string()))
+}
+
+fn expect<R: Read>(r: &mut R, expected: &str) -> io::Result<()> {
+    let found = read_string(r)?;
+    if found == expected { Ok(()) } else {
+        Err(invalid(format!("expected {:?}, found {:?}", expected, found)))
+    }
+}
+
+fn read_entry<R: Read>(r: &mut R) -> io::Result<NarEntry> {
+    expect(r, "(")?;
+    expect(r, "type")?;
+    let entry = match &read_string(r)?[..] {
+        "regular" => {
+            let executable = match &read_string(r)?[..] {
+                "executable" => { expect(r, "")?; expect(r, "contents")?; true }
+                "contents" => false,
+                tag => return Err(invalid(format!("unexpected {:?} in regular file", tag)))
+            };
+            NarEntry::Regular { executable, contents: read_bytes(r)? }
+        }
+        "symlink" => {
+            expect(r, "target")?;
+            NarEntry::Symlink(read_string(r)?)
+        }
+        "directory" => {
+            let mut entries = Vec::new();
+            loop {
+                match &read_string(r)?[..] {
+                    "entry" => {
+                        expect(r, "(")?;
+                        expect(r, "name")?;
+                        let name = read_string(r)?;
+                        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                            return Err(invalid(format!("invalid file name {:?}", name)))
+                        }
+                        expect(r, "node")?;
+                        entries.push((name, read_entry(r)?));
+                        expect(r, ")")?;
+                    }
+                    ")" => return Ok(NarEntry::Directory(entries)),
+                    tag => return Err(invalid(format!("unexpected {:?} in directory", tag)))
+                }
+            }
+        }
+        kind => return Err(invalid(format!("unknown file type {:?}", kind)))
+    };
+    expect(r, ")")?;
+    Ok(entry)
+}
+
+/// Reads a NAR, like produced by nix-store --dump
+pub fn read_nar<R: Read>(r: &mut R) -> io::Result<NarEntry> {
+    expect(r, NAR_MAGIC)?;
+    read_entry(r)
+}
+
+/// Whether bytes look like a NAR, rather than an export
+pub fn is_nar(bytes: &[u8]) -> bool {
+    let mut r = bytes;
+    matches!(read_string(&mut r), Ok(ref magic) if magic == NAR_MAGIC)
+}
+
+/// Reads the output of nix-store --export, which is a NAR and some metadata for each path
+pub fn read_export<R: Read>(r: &mut R) -> io::Result<Vec<ExportedPath>> {
+    let mut paths = Vec::new();
+    loop {
+        match read_u64(r)? {
+            0 => return Ok(paths),
+            1 => (),
+            n => return Err(invalid(format!("unexpected {} in export", n)))
+        }
+
+        let nar = read_nar(r)?;
+        if read_u64(r)? != EXPORT_MAGIC {
+            return Err(invalid(String::from("missing export magic")))
+        }
+        let path = read_string(r)?;
+        let references = (0..read_u64(r)?)
+            .map(|_| read_string(r))
+            .collect::<io::Result<_>>()?;
+        let deriver = Some(read_string(r)?).filter(|deriver| !deriver.is_empty());
+        // signatures were dropped from the format, but the flag is still there
+        if read_u64(r)? != 0 {
+            return Err(invalid(String::from("signed exports aren't supported")))
+        }
+
+        paths.push(ExportedPath { path, nar, references, deriver });
+    }
+}
+
+#[cfg(test)]
+pub fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    out.extend_from_slice(bytes);
+    out.resize(out.len() + (8 - bytes.len() % 8) % 8, 0);
+}
+
+#[cfg(test)]
+pub fn write_nar(out: &mut Vec<u8>, entry: &NarEntry) {
+    fn write_entry(out: &mut Vec<u8>, entry: &NarEntry) {
+        write_bytes(out, b"(");
+        write_bytes(out, b"type");
+        match entry {
+            NarEntry::Regular { executable, contents } => {
+                write_bytes(out, b"regular");
+                if *executable { write_bytes(out, b"executable"); write_bytes(out, b""); }
+                write_bytes(out, b"contents");
+                write_bytes(out, contents);
+            }
+            NarEntry::Symlink(target) => {
+                write_bytes(out, b"symlink");
+                write_bytes(out, b"target");
+                write_bytes(out, target.as_bytes());
+            }
+            NarEntry::Directory(entries) => {
+                write_bytes(out, b"directory");
+                for (name, entry) in entries {
+                    for tag in &["entry", "(", "name"] { write_bytes(out, tag.as_bytes()) }
+                    write_bytes(out, name.as_bytes());
+                    write_bytes(out, b"node");
+                    write_entry(out, entry);
+                    write_bytes(out, b")");
+                }
+            }
+        }
+        write_bytes(out, b")");
+    }
+
+    write_bytes(out, NAR_MAGIC.as_bytes());
+    write_entry(out, entry);
+}
+
+#[cfg(test)]
+pub fn write_export(out: &mut Vec<u8>, paths: &[ExportedPath]) {
+    for path in paths {
+        out.extend_from_slice(&1u64.to_le_bytes());
+        write_nar(out, &path.nar);
+        out.extend_from_slice(&EXPORT_MAGIC.to_le_bytes());
+        write_bytes(out, path.path.as_bytes());
+        out.extend_from_slice(&(path.references.len() as u64).to_le_bytes());
+        for reference in &path.references { write_bytes(out, reference.as_bytes()) }
+        write_bytes(out, path.deriver.as_deref().unwrap_or("").as_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+    }
+    out.extend_from_slice(&0u64.to_le_bytes());
+}
+
+#[test]
+fn read_strings() {
+    let mut out = Vec::new();
+    write_bytes(&mut out, b"regular");
+    write_bytes(&mut out, b"");
+    write_bytes(&mut out, b"contents");
+    assert_eq!(out.len(), 8 + 8 + 8 + 8 + 8);
+
+    let mut r = &out[..];
+    assert_eq!(read_string(&mut r).unwrap(), "regular");
+    assert_eq!(read_string(&mut r).unwrap(), "");
+    assert_eq!(read_string(&mut r).unwrap(), "contents");
+    assert!(read_string(&mut r).is_err());
+
+    let mut truncated = &out[..12];
+    assert!(read_string(&mut truncated).is_err());
+}
+
+#[test]
+fn nar_roundtrip() {
+    let tree = NarEntry::Directory(vec![
+        (String::from("bin"), NarEntry::Directory(vec![
+            (String::from("hello"), NarEntry::Regular { executable: true, contents: b"#!/bin/sh\n".to_vec() })
+        ])),
+        (String::from("lib"), NarEntry::Symlink(String::from("/nix/store/foo"))),
+        (String::from("README"), NarEntry::Regular { executable: false, contents: Vec::new() })
+    ]);
+    let mut nar = Vec::new();
+    write_nar(&mut nar, &tree);
+    assert!(is_nar(&nar));
+    assert_eq!(read_nar(&mut &nar[..]).unwrap(), tree);
+
+    // trailing garbage in a directory
+    let mut broken = nar.clone();
+    broken.truncate(nar.len() - 16);
+    write_bytes(&mut broken, b"garbage");
+    assert!(read_nar(&mut &broken[..]).is_err());
+}
+
+#[test]
+fn export_roundtrip() {
+    let paths = vec![ExportedPath {
+        path: String::from("/nix/store/fbx9ysbd6wplnkwk46a6hbafkqp5m5w5-xz-5.2.4.tar.bz2.drv"),
+        nar: NarEntry::Regular { executable: false, contents: include_bytes!("../assets/xz.tar.bz2.drv").to_vec() },
+        references: Vec::new(),
+        deriver: None
+    }];
+    let mut export = Vec::new();
+    write_export(&mut export, &paths);
+    assert!(!is_nar(&export));
+    assert_eq!(read_export(&mut &export[..]).unwrap(), paths);
+    assert!(read_export(&mut &export[..export.len() - 8]).is_err());
+}
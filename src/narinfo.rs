@@ -1,109 +1,167 @@
-use std::str;
+use std::{ fmt, str };
 
 use nom::{
-    parse_to,
     IResult,
     branch::alt,
-    sequence::{ preceded, terminated, tuple },
-    combinator::{ map, map_parser, opt, value },
-    bytes::streaming::{ tag, is_not },
-    character::streaming::newline
+    sequence::{ separated_pair, terminated },
+    combinator::{ all_consuming, opt, value },
+    multi::many0,
+    bytes::complete::{ tag, is_not },
+    character::complete::{ char, line_ending, newline }
 };
 
-#[derive(Debug, Clone)]
+use crate::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NarInfo {
     pub store_path: String,
     pub url: String,
     pub compression: String,
-    pub file_hash: String,
-    pub file_size: u64,
+    pub file_hash: Option<String>,
+    pub file_size: Option<u64>,
     pub nar_hash: String,
     pub nar_size: u64,
     pub references: Vec<String>,
     pub deriver: Option<String>,
-    pub sig: String
+    pub system: Option<String>,
+    pub ca: Option<String>,
+    pub sig: Vec<String>,
+    /// Fields we don't know about, in order of appearance
+    pub extra: Vec<(String, String)>
+}
+
+// nom 5 only has eof as a macro
+fn eof(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    nom::eof!(i,)
 }
 
+// The last field might lack its newline
 fn data(i: &[u8]) -> IResult<&[u8], &[u8]> {
-    alt((terminated(is_not("\n"), newline),
-         value(&b"\n"[..], newline)))(i)
-}
-
-fn string(i: &[u8]) -> IResult<&[u8], String> {
-    map(data, |b| String::from_utf8_lossy(&b).into_owned())(i)
-}
-
-fn string_list(i: &[u8]) -> IResult<&[u8], Vec<String>> {
-    map(string, |s| s.split_whitespace().map(str::to_owned).collect())(i)
-}
-
-fn size(i: &[u8]) -> IResult<&[u8], u64> {
-    map_parser(data, |i| parse_to!(i, u64))(i)
-}
-
-fn narinfo(i: &[u8]) -> IResult<&[u8], NarInfo> {
-    let (i,
-         (_, store_path, _, url, _, compression,
-          _, file_hash, _, file_size,
-          _, nar_hash, _, nar_size,
-          _, references, deriver,  _, sig)) =
-         tuple((tag("StorePath: "), string,
-                tag("URL: "), string,
-                tag("Compression: "), string,
-                tag("FileHash: "), string,
-                tag("FileSize: "), size,
-                tag("NarHash: "), string,
-                tag("NarSize: "), size,
-                tag("References: "), string_list,
-                opt(preceded(tag("Deriver: "), string)),
-                tag("Sig: "), string))(i)?;
-    Ok((i, NarInfo { store_path, url, compression,
-       file_hash, file_size, nar_hash, nar_size,
-       references, deriver, sig }))
-}
-
-/*
-named!(data, alt!(
-    terminated!(is_not!("\n"), newline)
-  | newline => { |_| &b"\n"[..] }
-));
-
-named!(string<String>,
-    map!(data, |b| String::from_utf8_lossy(&b).into_owned()));
-named!(string_list<Vec<String> >,
-    map!(string, |s| s.split_whitespace().map(str::to_owned).collect()));
-named!(size<u64>, flat_map!(data, parse_to!(u64)));
-
-named!(narinfo<NarInfo>,
-    do_parse!(
-        tag!("StorePath: ") >> store_path: string >>
-        tag!("URL: ") >> url: string >>
-        tag!("Compression: ") >> compression: string >>
-        tag!("FileHash: ") >> file_hash: string >>
-        tag!("FileSize: ") >> file_size: size >>
-        tag!("NarHash: ") >> nar_hash: string >>
-        tag!("NarSize: ") >> nar_size: size >>
-        tag!("References: ") >> references: string_list >>
-        deriver: opt!(preceded!(tag!("Deriver: "), string)) >>
-        tag!("Sig: ") >> sig: string >>
-        (NarInfo {
-            store_path, url,
-            compression,
+    alt((terminated(is_not("\n"), alt((line_ending, eof))),
+         value(&b"\n"[..], newline),
+         eof))(i)
+}
+
+fn string(b: &[u8]) -> String {
+    String::from_utf8_lossy(b).trim().to_owned()
+}
+
+fn string_list(s: &str) -> Vec<String> {
+    s.split_whitespace().map(str::to_owned).collect()
+}
+
+fn size(s: &str) -> Option<u64> {
+    s.parse().ok()
+}
+
+// e.g. "NarSize: 526128\n", the space after the colon is optional
+fn field(i: &[u8]) -> IResult<&[u8], (String, String)> {
+    let (i, (key, value)) =
+        separated_pair(is_not(":\n"), terminated(char(':'), opt(char(' '))), data)(i)?;
+    Ok((i, (string(key), string(value))))
+}
+
+fn fields(i: &[u8]) -> IResult<&[u8], Vec<(String, String)>> {
+    all_consuming(terminated(many0(field), many0(tag("\n"))))(i)
+}
+
+impl NarInfo {
+    /// Accepts fields in any order, as well as repeated Sig fields and unknown fields
+    pub fn parse(body: &[u8]) -> Result<Self, Error> {
+        let (_, fields) = fields(body)
+            .map_err(|_| Error::ParseNarInfo(String::from("not a list of fields")))?;
+
+        let (mut store_path, mut url, mut compression) = (None, None, None);
+        let (mut file_hash, mut file_size, mut nar_hash, mut nar_size) = (None, None, None, None);
+        let (mut references, mut deriver, mut system, mut ca) = (Vec::new(), None, None, None);
+        let (mut sig, mut extra) = (Vec::new(), Vec::new());
+
+        for (key, value) in fields {
+            let invalid = || Error::ParseNarInfo(format!("invalid {}: {}", key, value));
+            match &key[..] {
+                "StorePath" => store_path = Some(value),
+                "URL" => url = Some(value),
+                "Compression" => compression = Some(value),
+                "FileHash" => file_hash = Some(value),
+                "FileSize" => file_size = Some(size(&value).ok_or_else(invalid)?),
+                "NarHash" => nar_hash = Some(value),
+                "NarSize" => nar_size = Some(size(&value).ok_or_else(invalid)?),
+                "References" => references = string_list(&value),
+                // Nix writes this when it doesn't know the deriver
+                "Deriver" if value == "unknown-deriver" => (),
+                "Deriver" => deriver = Some(value),
+                "System" => system = Some(value),
+                "CA" => ca = Some(value),
+                "Sig" => sig.push(value),
+                _ => extra.push((key, value))
+            }
+        }
+
+        let missing = |key| Error::ParseNarInfo(format!("missing {}", key));
+        Ok(NarInfo {
+            store_path: store_path.ok_or_else(|| missing("StorePath"))?,
+            url: url.ok_or_else(|| missing("URL"))?,
+            // the default Nix assumes for old narinfos
+            compression: compression.unwrap_or_else(|| String::from("bzip2")),
             file_hash, file_size,
-            nar_hash, nar_size,
-            references,
-            deriver, sig
+            nar_hash: nar_hash.ok_or_else(|| missing("NarHash"))?,
+            nar_size: nar_size.ok_or_else(|| missing("NarSize"))?,
+            references, deriver, system, ca, sig, extra
         })
-    )
-);*/
+    }
+}
 
-impl NarInfo {
-    pub fn from(body: &[u8]) -> Option<Self> {
-        if &body[..] == b"404" { return None }
-        narinfo(body).ok().map(|(_rest, info)| info)
+impl fmt::Display for NarInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "StorePath: {}", self.store_path)?;
+        writeln!(f, "URL: {}", self.url)?;
+        writeln!(f, "Compression: {}", self.compression)?;
+        if let Some(file_hash) = &self.file_hash { writeln!(f, "FileHash: {}", file_hash)?; }
+        if let Some(file_size) = self.file_size { writeln!(f, "FileSize: {}", file_size)?; }
+        writeln!(f, "NarHash: {}", self.nar_hash)?;
+        writeln!(f, "NarSize: {}", self.nar_size)?;
+        writeln!(f, "References: {}", self.references.join(" "))?;
+        if let Some(deriver) = &self.deriver { writeln!(f, "Deriver: {}", deriver)?; }
+        if let Some(system) = &self.system { writeln!(f, "System: {}", system)?; }
+        for sig in &self.sig { writeln!(f, "Sig: {}", sig)?; }
+        if let Some(ca) = &self.ca { writeln!(f, "CA: {}", ca)?; }
+        for (key, value) in &self.extra { writeln!(f, "{}: {}", key, value)?; }
+        Ok(())
     }
 }
 
+/// An unsigned narinfo for /nix/store/<name>, for tests
+#[cfg(test)]
+pub(crate) fn test_narinfo(name: &str, references: &[&str]) -> NarInfo {
+    NarInfo {
+        store_path: format!("/nix/store/{}", name),
+        url: format!("nar/{}.nar.xz", name),
+        compression: String::from("xz"),
+        file_hash: None, file_size: Some(10),
+        nar_hash: String::from("sha256:05x4098vin5klbk9fjfarrj2727kp4il3cmp21w8z3m097jlcjh8"),
+        nar_size: 30,
+        references: references.iter().map(|&name| name.to_owned()).collect(),
+        deriver: None, system: None, ca: None,
+        sig: Vec::new(), extra: Vec::new()
+    }
+}
+
+/// An empty in-memory binary cache, at memory://name
+#[cfg(test)]
+pub(crate) fn test_cache(name: &str) -> crate::binary_cache::MemoryBinaryCache {
+    crate::binary_cache::MemoryBinaryCache::new(url::Url::parse(&format!("memory://{}", name)).unwrap())
+}
+
+/// Registers drvs by .drv file name, inputs before the derivations depending on them
+#[cfg(test)]
+pub(crate) fn test_store(drvs: &[(&str, crate::derivation::Drv)]) -> crate::StoreCache {
+    let mut store = crate::StoreCache::default();
+    for (name, drv) in drvs {
+        store.discover_build_time_closure(crate::StoreHash::from_name(name).unwrap(), drv).unwrap();
+    }
+    store
+}
+
 #[test]
 fn parse_empty_data() {
     assert!(data(b"\n").is_ok());
@@ -112,16 +170,71 @@ fn parse_empty_data() {
 
 #[test]
 fn parse_size() {
-    assert_eq!(size(b"20971\n"), Ok((&b""[..], 20971)));
+    assert_eq!(size("20971"), Some(20971));
+    assert_eq!(size("-1"), None);
+}
+
+#[test]
+fn parse_field() {
+    assert_eq!(field(b"NarSize: 526128\n"), Ok((&b""[..], (String::from("NarSize"), String::from("526128")))));
+    assert_eq!(field(b"References: \n"), Ok((&b""[..], (String::from("References"), String::new()))));
+    assert_eq!(field(b"CA:fixed:r:sha256:abc\n"), Ok((&b""[..], (String::from("CA"), String::from("fixed:r:sha256:abc")))));
+    assert_eq!(field(b"NarSize: 526128"), Ok((&b""[..], (String::from("NarSize"), String::from("526128")))));
+    assert_eq!(field(b"References:"), Ok((&b""[..], (String::from("References"), String::new()))));
 }
 
 #[test]
 fn parse_narinfo() {
-    let info = narinfo(include_bytes!("../assets/blender.narinfo")); 
+    let info = NarInfo::parse(include_bytes!("../assets/blender.narinfo"));
     println!("{:?}", info);
     assert!(info.is_ok());
 
-    let info = narinfo(include_bytes!("../assets/dejagnu.narinfo")); 
+    let info = NarInfo::parse(include_bytes!("../assets/dejagnu.narinfo"));
     println!("{:?}", info);
-    assert!(info.is_ok());
+    let info = info.unwrap();
+    assert_eq!(info.file_size, Some(526212));
+    assert!(info.references.is_empty());
+    assert_eq!(info.sig.len(), 1);
+}
+
+#[test]
+fn parse_unordered_narinfo() {
+    let info = NarInfo::parse(b"\
+StorePath: /nix/store/s4vdsv44p998yw22a2fmargh6bvcs6cz-dejagnu-1.6.1.tar.gz
+NarHash: sha256:05x4098vin5klbk9fjfarrj2727kp4il3cmp21w8z3m097jlcjh8
+URL: nar/05x4098vin5klbk9fjfarrj2727kp4il3cmp21w8z3m097jlcjh8.nar
+NarSize: 526128
+Sig: cache.example.org-1:first
+References: s4vdsv44p998yw22a2fmargh6bvcs6cz-dejagnu-1.6.1.tar.gz
+System: x86_64-linux
+CA: fixed:sha256:1l2pz7ic2rnbmf7pyhh2g1jmrgb8hfp8l0yv0rwr3qs4w83lk2lj
+Sig: cache.example.org-2:second
+Deriver: unknown-deriver
+X-Served-By: harmonia
+").unwrap();
+
+    assert_eq!(info.compression, "bzip2");
+    assert_eq!(info.file_hash, None);
+    assert_eq!(info.file_size, None);
+    assert_eq!(info.nar_size, 526128);
+    assert_eq!(info.deriver, None);
+    assert_eq!(info.system.as_deref(), Some("x86_64-linux"));
+    assert!(info.ca.is_some());
+    assert_eq!(info.sig, vec!["cache.example.org-1:first", "cache.example.org-2:second"]);
+    assert_eq!(info.extra, vec![(String::from("X-Served-By"), String::from("harmonia"))]);
+
+    assert_eq!(NarInfo::parse(info.to_string().as_bytes()).unwrap(), info);
+}
+
+#[test]
+fn parse_narinfo_without_final_newline() {
+    let body = include_bytes!("../assets/dejagnu.narinfo");
+    let trimmed = body.strip_suffix(b"\n").unwrap();
+    assert_eq!(NarInfo::parse(trimmed).unwrap(), NarInfo::parse(body).unwrap());
+}
+
+#[test]
+fn parse_incomplete_narinfo() {
+    assert!(NarInfo::parse(b"StorePath: /nix/store/s4vdsv44p998yw22a2fmargh6bvcs6cz-dejagnu-1.6.1.tar.gz\n").is_err());
+    assert!(NarInfo::parse(b"404").is_err());
 }
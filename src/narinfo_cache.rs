@@ -0,0 +1,180 @@
+use std::{
+    env,
+    path::{ Path, PathBuf },
+    time::{ Duration, SystemTime, UNIX_EPOCH }
+};
+
+use rusqlite::{ params, Connection, OptionalExtension, Transaction };
+use url::Url;
+use log::debug;
+
+use crate::{ StoreHash, narinfo::NarInfo };
+
+// Bump when the schema changes, old caches are simply left behind
+const SCHEMA: &str = "
+    create table if not exists NarInfos (
+        cache       text not null,
+        hashPart    text not null,
+        present     integer not null,
+        storePath   text,
+        url         text,
+        compression text,
+        fileHash    text,
+        fileSize    integer,
+        narHash     text,
+        narSize     integer,
+        refs        text,
+        deriver     text,
+        system      text,
+        ca          text,
+        sigs        text,
+        extra       text,
+        timestamp   integer not null,
+        primary key (cache, hashPart)
+    );
+";
+
+/// Outcome of a previous lookup of a path in a binary cache
+#[derive(Debug, Clone)]
+pub enum CachedNarInfo {
+    Present(Box<NarInfo>),
+    Absent
+}
+
+/// Persistent cache of .narinfo lookups, similar to Nix's binary-cache-v6.sqlite
+pub struct NarInfoCache {
+    conn: Connection,
+    positive_ttl: Duration,
+    negative_ttl: Duration
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl NarInfoCache {
+    /// e.g. ~/.cache/nix-weather/narinfo-v2.sqlite
+    pub fn default_path() -> Option<PathBuf> {
+        env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .map(|dir| dir.join("nix-weather").join("narinfo-v2.sqlite"))
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, positive_ttl: Duration, negative_ttl: Duration) -> rusqlite::Result<Self> {
+        debug!("opening narinfo cache {}", path.as_ref().display());
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(NarInfoCache { conn, positive_ttl, negative_ttl })
+    }
+
+    pub fn in_memory(positive_ttl: Duration, negative_ttl: Duration) -> rusqlite::Result<Self> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(NarInfoCache { conn, positive_ttl, negative_ttl })
+    }
+
+    /// Group many inserts, committing each one on its own is slow
+    pub fn transaction(&self) -> rusqlite::Result<Transaction<'_>> {
+        self.conn.unchecked_transaction()
+    }
+
+    pub fn lookup(&self, cache: &Url, hash: StoreHash) -> rusqlite::Result<Option<CachedNarInfo>> {
+        let positive_cutoff = now() - self.positive_ttl.as_secs() as i64;
+        let negative_cutoff = now() - self.negative_ttl.as_secs() as i64;
+
+        self.conn.query_row(
+            "select present, storePath, url, compression, fileHash, fileSize,
+                    narHash, narSize, refs, deriver, system, ca, sigs, extra
+             from NarInfos
+             where cache = ?1 and hashPart = ?2
+               and ((present = 1 and timestamp > ?3) or (present = 0 and timestamp > ?4))",
+            params![cache.as_str(), hash.to_str(), positive_cutoff, negative_cutoff],
+            |row| {
+                if !row.get::<_, bool>(0)? { return Ok(CachedNarInfo::Absent) }
+
+                let split = |s: String| s.split_whitespace().map(str::to_owned).collect();
+                let extra: String = row.get(13)?;
+                Ok(CachedNarInfo::Present(Box::new(NarInfo {
+                    store_path: row.get(1)?,
+                    url: row.get(2)?,
+                    compression: row.get(3)?,
+                    file_hash: row.get(4)?,
+                    file_size: row.get::<_, Option<i64>>(5)?.map(|size| size as u64),
+                    nar_hash: row.get(6)?,
+                    nar_size: row.get::<_, i64>(7)? as u64,
+                    references: split(row.get(8)?),
+                    deriver: row.get(9)?,
+                    system: row.get(10)?,
+                    ca: row.get(11)?,
+                    sig: split(row.get(12)?),
+                    extra: serde_json::from_str(&extra).unwrap_or_default()
+                })))
+            }).optional()
+    }
+
+    pub fn insert(&self, cache: &Url, hash: StoreHash, narinfo: Option<&NarInfo>) -> rusqlite::Result<()> {
+        match narinfo {
+            Some(narinfo) => self.conn.execute(
+                "insert or replace into NarInfos
+                    (cache, hashPart, present, storePath, url, compression, fileHash, fileSize,
+                     narHash, narSize, refs, deriver, system, ca, sigs, extra, timestamp)
+                 values (?1, ?2, 1, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+                params![cache.as_str(), hash.to_str(),
+                        narinfo.store_path, narinfo.url, narinfo.compression,
+                        narinfo.file_hash, narinfo.file_size.map(|size| size as i64),
+                        narinfo.nar_hash, narinfo.nar_size as i64,
+                        narinfo.references.join(" "), narinfo.deriver,
+                        narinfo.system, narinfo.ca, narinfo.sig.join(" "),
+                        serde_json::to_string(&narinfo.extra).unwrap_or_default(),
+                        now()]),
+            None => self.conn.execute(
+                "insert or replace into NarInfos (cache, hashPart, present, timestamp)
+                 values (?1, ?2, 0, ?3)",
+                params![cache.as_str(), hash.to_str(), now()])
+        }.map(|_| ())
+    }
+
+    /// Forget every lookup, positive or negative
+    pub fn purge(&self) -> rusqlite::Result<()> {
+        self.conn.execute("delete from NarInfos", []).map(|_| ())
+    }
+}
+
+#[test]
+fn cache_roundtrip() {
+    let cache = NarInfoCache::in_memory(Duration::from_secs(60), Duration::from_secs(60)).unwrap();
+    let root = Url::parse("https://cache.nixos.org").unwrap();
+    let mut narinfo = NarInfo::parse(include_bytes!("../assets/dejagnu.narinfo")).unwrap();
+    narinfo.extra.push((String::from("X-Served-By"), String::from("harmonia")));
+    let hash = StoreHash::from_path(&narinfo.store_path).unwrap();
+    let missing = StoreHash::from_name("00000000000000000000000000000000").unwrap();
+
+    assert!(cache.lookup(&root, hash).unwrap().is_none());
+
+    cache.insert(&root, hash, Some(&narinfo)).unwrap();
+    cache.insert(&root, missing, None).unwrap();
+
+    match cache.lookup(&root, hash).unwrap() {
+        Some(CachedNarInfo::Present(cached)) => assert_eq!(*cached, narinfo),
+        other => panic!("expected cached narinfo, got {:?}", other)
+    }
+    assert!(matches!(cache.lookup(&root, missing).unwrap(), Some(CachedNarInfo::Absent)));
+
+    let other_root = Url::parse("https://example.org").unwrap();
+    assert!(cache.lookup(&other_root, hash).unwrap().is_none());
+
+    cache.purge().unwrap();
+    assert!(cache.lookup(&root, hash).unwrap().is_none());
+}
+
+#[test]
+fn cache_expiry() {
+    let cache = NarInfoCache::in_memory(Duration::from_secs(60), Duration::from_secs(0)).unwrap();
+    let root = Url::parse("https://cache.nixos.org").unwrap();
+    let missing = StoreHash::from_name("00000000000000000000000000000000").unwrap();
+
+    cache.insert(&root, missing, None).unwrap();
+    assert!(cache.lookup(&root, missing).unwrap().is_none());
+}
@@ -0,0 +1,346 @@
+use std::collections::{ HashMap, HashSet };
+
+use serde_derive::Serialize;
+use log::{ trace, warn };
+
+use crate::{ StoreHash, StoreItem, StoreCache };
+
+/// What realising a set of outputs would do, like nix-store --realise --dry-run
+#[derive(Default, Debug, Serialize)]
+pub struct Plan {
+    /// Derivations that will be built, as .drv store paths
+    pub will_build: Vec<String>,
+    /// Fixed-output derivations that will download sources from upstream
+    pub will_fetch_upstream: Vec<String>,
+    /// Paths that will be substituted from a binary cache
+    pub will_fetch: Vec<String>,
+    /// Compressed size of everything in will_fetch
+    pub download_size: u64,
+    /// Uncompressed size of everything in will_fetch
+    pub nar_size: u64,
+    /// Paths that are neither substitutable, nor have a known derivation,
+    /// or references of substituted paths that can't be substituted themselves
+    pub unknown: Vec<String>
+}
+
+struct Planner<'a> {
+    store: &'a StoreCache,
+    plan: Plan,
+    fetched: HashSet<StoreHash>,
+    built: HashSet<StoreHash>
+}
+
+impl<'a> Planner<'a> {
+    fn is_valid(&self, hash: StoreHash) -> bool {
+        matches!(self.store.get(&hash), Some(StoreItem::Present(_)) | Some(StoreItem::Source(_)))
+    }
+
+    // Only whether there is a trusted narinfo, whatever the deriver says
+    fn is_substitutable(&self, hash: StoreHash) -> bool {
+        matches!(self.store.get(&hash), Some(StoreItem::NarInfo(_)))
+    }
+
+    fn want_path(&mut self, hash: StoreHash) {
+        if self.is_valid(hash) || self.fetched.contains(&hash) { return }
+
+        if self.store.is_substitutable(hash) {
+            self.substitute(hash);
+        } else {
+            match self.store.deriver_of(hash) {
+                Some(deriver) => self.want_outputs(deriver, &[hash]),
+                None => self.plan.unknown.push(self.store.path_of(hash))
+            }
+        }
+    }
+
+    fn substitute(&mut self, hash: StoreHash) {
+        if !self.fetched.insert(hash) { return }
+        if let Some(StoreItem::NarInfo(narinfo)) = self.store.get(&hash) {
+            trace!("will fetch {}", narinfo.store_path);
+            self.plan.will_fetch.push(narinfo.store_path.clone());
+            self.plan.download_size += narinfo.file_size.unwrap_or(0);
+            self.plan.nar_size += narinfo.nar_size;
+        }
+
+        // Nix substitutes references as opaque paths, without looking at their derivers
+        for reference in self.store.dependencies(hash) {
+            if self.is_valid(reference) || self.fetched.contains(&reference) { continue }
+            if self.is_substitutable(reference) {
+                self.substitute(reference);
+            } else {
+                self.plan.unknown.push(self.store.path_of(reference));
+            }
+        }
+    }
+
+    // Outputs are only substituted if all missing ones can be, otherwise
+    // the derivation is built, which produces all of its outputs anyway
+    fn want_outputs(&mut self, drv_hash: StoreHash, outputs: &[StoreHash]) {
+        if self.built.contains(&drv_hash) { return }
+        let allows_substitutes = self.store.drv_attrs(drv_hash).is_none_or(|attrs| attrs.allows_substitutes());
+
+        let invalid: Vec<StoreHash> = outputs.iter().copied()
+            .filter(|&output| !self.is_valid(output))
+            .collect();
+        if invalid.is_empty() { return }
+
+        if allows_substitutes && invalid.iter().all(|&output| self.is_substitutable(output)) {
+            for output in invalid { self.substitute(output) }
+        } else {
+            self.build(drv_hash);
+        }
+    }
+
+    fn build(&mut self, drv_hash: StoreHash) {
+        if !self.built.insert(drv_hash) { return }
+        let store = self.store;
+        let drv = match store.get(&drv_hash) {
+            Some(StoreItem::Drv(drv)) => drv,
+            _ => { self.plan.unknown.push(store.path_of(drv_hash)); return }
+        };
+
+        trace!("will build {}", store.path_of(drv_hash));
+        if drv.is_upstream_fetch() {
+            self.plan.will_fetch_upstream.push(store.path_of(drv_hash));
+        } else {
+            self.plan.will_build.push(store.path_of(drv_hash));
+        }
+
+        for input in &drv.input_drvs {
+            let input_hash = match StoreHash::from_path(&input.path) {
+                Ok(input_hash) => input_hash,
+                Err(e) => { warn!("ignoring input of {}: {}", store.path_of(drv_hash), e); continue }
+            };
+
+            match input.resolve(store) {
+                Ok(outputs) => self.want_outputs(input_hash, &outputs),
+                Err(e) => warn!("ignoring input of {}: {}", store.path_of(drv_hash), e)
+            }
+        }
+    }
+}
+
+impl Plan {
+    /// Plans realising roots, which are usually outputs of derivations in store,
+    /// or derivations themselves for outputs that are only known after building
+    pub fn for_outputs(roots: &[StoreHash], store: &StoreCache) -> Self {
+        let mut planner = Planner {
+            store,
+            plan: Plan::default(),
+            fetched: HashSet::new(),
+            built: HashSet::new()
+        };
+
+        // outputs of the same derivation have to be decided on together
+        let mut by_deriver: HashMap<StoreHash, Vec<StoreHash>> = HashMap::new();
+        for &root in roots {
+            match (store.get(&root), store.deriver_of(root)) {
+                // outputs only known after building
+                (Some(StoreItem::Drv(_)), _) => planner.build(root),
+                (_, Some(deriver)) => by_deriver.entry(deriver).or_default().push(root),
+                (_, None) => planner.want_path(root)
+            }
+        }
+        for (deriver, outputs) in by_deriver {
+            planner.want_outputs(deriver, &outputs);
+        }
+
+        let mut plan = planner.plan;
+        plan.will_build.sort();
+        plan.will_fetch_upstream.sort();
+        plan.will_fetch.sort();
+        plan.unknown.sort();
+        plan.unknown.dedup();
+        plan
+    }
+}
+
+#[test]
+fn plan_builds_and_fetches() {
+    use crate::{ derivation::test_drv, narinfo::{ test_narinfo, test_store } };
+
+    let drvs = [
+        ("cccccccccccccccccccccccccccccccc-c.drv", test_drv("11111111111111111111111111111111-c", &[])),
+        ("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv", test_drv("22222222222222222222222222222222-b",
+                                                           &["cccccccccccccccccccccccccccccccc-c.drv"])),
+        ("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv", test_drv("33333333333333333333333333333333-a",
+                                                           &["bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv"]))
+    ];
+    let mut store = test_store(&drvs);
+
+    let c_out = StoreHash::from_name("11111111111111111111111111111111").unwrap();
+    let mut narinfo = test_narinfo("11111111111111111111111111111111-c", &[]);
+    narinfo.deriver = Some(String::from("cccccccccccccccccccccccccccccccc-c.drv"));
+    store.items.insert(c_out, StoreItem::NarInfo(Box::new(narinfo)));
+
+    let a_out = StoreHash::from_name("33333333333333333333333333333333").unwrap();
+    let plan = Plan::for_outputs(&[a_out], &store);
+    assert_eq!(plan.will_build, vec!["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv",
+                                     "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv"]);
+    assert_eq!(plan.will_fetch, vec!["/nix/store/11111111111111111111111111111111-c"]);
+    assert_eq!((plan.download_size, plan.nar_size), (10, 30));
+    assert!(plan.unknown.is_empty());
+
+    // Nix ignores the narinfo of c if c doesn't allow substitutes
+    let c_drv = StoreHash::from_name("cccccccccccccccccccccccccccccccc").unwrap();
+    if let Some(StoreItem::Drv(drv)) = store.items.get_mut(&c_drv) {
+        drv.env.push((String::from("allowSubstitutes"), String::new()));
+    }
+    let plan = Plan::for_outputs(&[a_out], &store);
+    assert_eq!(plan.will_build.len(), 3);
+    assert!(plan.will_fetch.is_empty());
+
+    // unless c is already there
+    let c_out = StoreHash::from_name("11111111111111111111111111111111").unwrap();
+    let narinfo = store.items.insert(c_out, StoreItem::Present(String::from("c"))).unwrap();
+    let plan = Plan::for_outputs(&[a_out], &store);
+    assert_eq!(plan.will_build, vec!["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv",
+                                     "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv"]);
+    store.items.insert(c_out, narinfo);
+
+    // once b is available, nothing below it is needed anymore
+    store.items.insert(StoreHash::from_name("22222222222222222222222222222222").unwrap(),
+                       StoreItem::Present(String::from("b")));
+    let plan = Plan::for_outputs(&[a_out], &store);
+    assert_eq!(plan.will_build, vec!["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv"]);
+    assert!(plan.will_fetch.is_empty());
+}
+
+#[test]
+fn plan_fetches_sources_upstream() {
+    use crate::{ Closure, derivation::test_drv, narinfo::test_store };
+
+    let mut src = test_drv("11111111111111111111111111111111-src.tar.gz", &[]);
+    src.builder = String::from("builtin:fetchurl");
+    let drvs = [
+        ("cccccccccccccccccccccccccccccccc-src.tar.gz.drv", src),
+        ("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv", test_drv("33333333333333333333333333333333-a",
+                                                           &["cccccccccccccccccccccccccccccccc-src.tar.gz.drv"]))
+    ];
+    let store = test_store(&drvs);
+
+    let a_out = StoreHash::from_name("33333333333333333333333333333333").unwrap();
+    let plan = Plan::for_outputs(&[a_out], &store);
+    assert_eq!(plan.will_build, vec!["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv"]);
+    assert_eq!(plan.will_fetch_upstream, vec!["/nix/store/cccccccccccccccccccccccccccccccc-src.tar.gz.drv"]);
+
+    let mut closure = Closure::empty();
+    closure.add_runtime_closure_of(a_out, &store);
+    let stats = closure.coverage_statistics(&store);
+    assert_eq!(stats.missing, vec!["a"]);
+    assert_eq!(stats.upstream, vec!["src.tar.gz"]);
+}
+
+#[test]
+fn plan_fetches_inputs_of_unsubstituted() {
+    use crate::{ binary_cache::BinaryCache, derivation::test_drv, narinfo::{ test_cache, test_narinfo, test_store } };
+
+    let mut local = test_drv("22222222222222222222222222222222-local", &["cccccccccccccccccccccccccccccccc-stdenv.drv"]);
+    local.env.push((String::from("allowSubstitutes"), String::new()));
+    let drvs = [
+        ("cccccccccccccccccccccccccccccccc-stdenv.drv", test_drv("11111111111111111111111111111111-stdenv", &[])),
+        ("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-local.drv", local),
+        ("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv", test_drv("33333333333333333333333333333333-a",
+                                                           &["bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-local.drv"]))
+    ];
+    let mut store = test_store(&drvs);
+
+    // local was pushed to the cache, but Nix won't fetch it, so stdenv is needed to build it
+    let cache = test_cache("test");
+    cache.insert(test_narinfo("11111111111111111111111111111111-stdenv", &[])).unwrap();
+    cache.insert(test_narinfo("22222222222222222222222222222222-local", &[])).unwrap();
+    let caches: [Box<dyn BinaryCache>; 1] = [Box::new(cache)];
+
+    let a_out = StoreHash::from_name("33333333333333333333333333333333").unwrap();
+    futures::executor::block_on(store.fetch_narinfo(&[a_out], &caches, 4, None, None, None));
+
+    let plan = Plan::for_outputs(&[a_out], &store);
+    assert_eq!(plan.will_build, vec!["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv",
+                                     "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-local.drv"]);
+    assert_eq!(plan.will_fetch, vec!["/nix/store/11111111111111111111111111111111-stdenv"]);
+
+    // the cached local output doesn't count as found either
+    let mut closure = crate::Closure::empty();
+    closure.add_runtime_closure_of(StoreHash::from_name("22222222222222222222222222222222").unwrap(), &store);
+    let stats = closure.coverage_statistics(&store);
+    assert_eq!((stats.found, stats.local.clone()), (0, vec![String::from("local")]));
+}
+
+#[test]
+fn plan_fetches_inputs_of_untrusted() {
+    use base64::{ Engine, engine::general_purpose::STANDARD as BASE64 };
+    use ed25519_dalek::{ Signer, SigningKey };
+    use crate::{
+        binary_cache::BinaryCache, derivation::test_drv, narinfo::{ test_cache, test_narinfo, test_store },
+        signature::PublicKey
+    };
+
+    let drvs = [
+        ("cccccccccccccccccccccccccccccccc-stdenv.drv", test_drv("11111111111111111111111111111111-stdenv", &[])),
+        ("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv", test_drv("22222222222222222222222222222222-b",
+                                                           &["cccccccccccccccccccccccccccccccc-stdenv.drv"])),
+        ("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv", test_drv("33333333333333333333333333333333-a",
+                                                           &["bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv"]))
+    ];
+    let mut store = test_store(&drvs);
+
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let trusted_keys: Vec<PublicKey> = vec![format!("test-1:{}", BASE64.encode(key.verifying_key().as_bytes()))
+        .parse().unwrap()];
+    let narinfo = |name: &str, signed: bool| {
+        let mut narinfo = test_narinfo(name, &[]);
+        if signed {
+            let sig = key.sign(narinfo.fingerprint().as_bytes());
+            narinfo.sig.push(format!("test-1:{}", BASE64.encode(sig.to_bytes())));
+        }
+        narinfo
+    };
+    // b is cached, but Nix doesn't trust it and builds it with stdenv instead
+    let cache = test_cache("test");
+    cache.insert(narinfo("11111111111111111111111111111111-stdenv", true)).unwrap();
+    cache.insert(narinfo("22222222222222222222222222222222-b", false)).unwrap();
+    let caches: [Box<dyn BinaryCache>; 1] = [Box::new(cache)];
+
+    let a_out = StoreHash::from_name("33333333333333333333333333333333").unwrap();
+    futures::executor::block_on(store.fetch_narinfo(&[a_out], &caches, 4, None, Some(&trusted_keys), None));
+
+    let plan = Plan::for_outputs(&[a_out], &store);
+    assert_eq!(plan.will_build, vec!["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv",
+                                     "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b.drv"]);
+    assert_eq!(plan.will_fetch, vec!["/nix/store/11111111111111111111111111111111-stdenv"]);
+}
+
+#[test]
+fn plan_fetches_references_whatever_their_deriver() {
+    use crate::{ derivation::test_drv, narinfo::{ test_narinfo, test_store } };
+
+    let mut local = test_drv("22222222222222222222222222222222-local", &[]);
+    local.env.push((String::from("allowSubstitutes"), String::new()));
+    let drvs = [
+        ("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-local.drv", local),
+        ("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a.drv", test_drv("33333333333333333333333333333333-a",
+                                                           &["bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-local.drv"]))
+    ];
+    let mut store = test_store(&drvs);
+
+    // a refers to the cached local, and to something no cache has
+    let (a, local, gone) = ("33333333333333333333333333333333-a", "22222222222222222222222222222222-local",
+                            "44444444444444444444444444444444-gone");
+    let mut local_narinfo = test_narinfo(local, &[]);
+    local_narinfo.deriver = Some(String::from("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-local.drv"));
+    for narinfo in [test_narinfo(a, &[local, gone]), local_narinfo] {
+        let hash = StoreHash::from_path(&narinfo.store_path).unwrap();
+        store.items.insert(hash, StoreItem::NarInfo(Box::new(narinfo)));
+    }
+
+    let plan = Plan::for_outputs(&[StoreHash::from_name(a).unwrap()], &store);
+    assert!(plan.will_build.is_empty());
+    assert_eq!(plan.will_fetch, vec!["/nix/store/22222222222222222222222222222222-local",
+                                     "/nix/store/33333333333333333333333333333333-a"]);
+    assert_eq!(plan.unknown, vec!["/nix/store/44444444444444444444444444444444"]);
+
+    // asked for directly, local is built all the same
+    let plan = Plan::for_outputs(&[StoreHash::from_name(local).unwrap()], &store);
+    assert_eq!(plan.will_build, vec!["/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-local.drv"]);
+    assert!(plan.will_fetch.is_empty());
+}
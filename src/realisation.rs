@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+
+use serde_derive::Deserialize;
+
+use crate::{ Error, hash::to_hex };
+
+/// Where a floating content-addressed output ended up, as served under realisations/<id>.doi
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Realisation {
+    /// e.g. sha256:<hashDerivationModulo>!out
+    pub id: String,
+    /// Usually without the store directory, e.g. kz4...-hello-2.10
+    pub out_path: String,
+    #[serde(default)]
+    pub signatures: Vec<String>,
+    #[serde(default)]
+    pub dependent_realisations: BTreeMap<String, String>
+}
+
+impl Realisation {
+    pub fn parse(body: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(body).map_err(|e| Error::ParseRealisation(e.to_string()))
+    }
+
+    /// The id of output of a derivation, given its hash modulo
+    pub fn id(drv_hash: &[u8; 32], output: &str) -> String {
+        format!("sha256:{}!{}", to_hex(drv_hash), output)
+    }
+}
+
+#[test]
+fn parse_realisation() {
+    let realisation = Realisation::parse(br#"{
+        "dependentRealisations": {},
+        "id": "sha256:94f2ab4ee71ab0c1d3d8c9d6fbf5fd33e8d0fd21c2f2c8e46d0cbe6b8c8c8a65!out",
+        "outPath": "kz4f3i5xlahgvjd9b8fs0jxzdl2h2hxk-hello-2.12",
+        "signatures": ["cache.example.org-1:c2ln"]
+    }"#).unwrap();
+    assert_eq!(realisation.out_path, "kz4f3i5xlahgvjd9b8fs0jxzdl2h2hxk-hello-2.12");
+    assert_eq!(realisation.signatures.len(), 1);
+    assert_eq!(Realisation::id(&[0; 32], "out"), format!("sha256:{}!out", "0".repeat(64)));
+
+    assert!(Realisation::parse(b"{}").is_err());
+}
@@ -0,0 +1,137 @@
+use std::{ convert::TryInto, str::FromStr };
+
+use base64::{ Engine, engine::general_purpose::STANDARD as BASE64 };
+use ed25519_dalek::{ Signature, VerifyingKey };
+use log::trace;
+
+use crate::{
+    Error, StoreHash, STORE_DIR,
+    hash::{ from_base32, make_fixed_output_path, make_store_path },
+    narinfo::NarInfo
+};
+
+pub const CACHE_NIXOS_ORG_KEY: &str = "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
+
+/// A key from trusted-public-keys, e.g. cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    pub name: String,
+    key: VerifyingKey
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidPublicKey(s.to_owned());
+        let (name, key) = s.split_once(':').ok_or_else(invalid)?;
+        let key: [u8; 32] = BASE64.decode(key).ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(invalid)?;
+        let key = VerifyingKey::from_bytes(&key).map_err(|_| invalid())?;
+        Ok(PublicKey { name: name.to_owned(), key })
+    }
+}
+
+impl PublicKey {
+    /// Checks a signature of the form <key name>:<base64 signature>
+    pub fn verify(&self, fingerprint: &str, sig: &str) -> bool {
+        match sig.split_once(':') {
+            Some((name, sig)) if name == self.name => BASE64.decode(sig).ok()
+                .and_then(|sig| Signature::from_slice(&sig).ok())
+                .map(|sig| self.key.verify_strict(fingerprint.as_bytes(), &sig).is_ok())
+                .unwrap_or(false),
+            _ => false
+        }
+    }
+}
+
+impl NarInfo {
+    /// What Nix signs, e.g. 1;/nix/store/...-hello-2.10;sha256:...;206008;/nix/store/...-glibc-2.27
+    pub fn fingerprint(&self) -> String {
+        let references: Vec<String> = self.references.iter()
+            .map(|reference| format!("{}/{}", STORE_DIR, reference))
+            .collect();
+        format!("1;{};{};{};{}", self.store_path, self.nar_hash, self.nar_size, references.join(","))
+    }
+
+    /// Where the CA field says this path should be, e.g. for fixed:r:sha256:<base32>, like Nix's makeFixedOutputPathFromCA
+    pub fn content_addressed_path(&self) -> Option<String> {
+        let (_, name) = StoreHash::split_path(&self.store_path).ok()?;
+        let self_name = self.store_path.strip_prefix(STORE_DIR)?.trim_start_matches('/');
+        let has_self_reference = self.references.iter().any(|reference| reference == self_name);
+        let mut references: Vec<&String> = self.references.iter()
+            .filter(|reference| *reference != self_name)
+            .collect();
+        references.sort();
+
+        // e.g. :/nix/store/...-glibc:self
+        let mut refs = String::new();
+        for reference in &references { refs.push_str(&format!(":{}/{}", STORE_DIR, reference)); }
+        if has_self_reference { refs.push_str(":self") }
+
+        let (method, hash) = self.ca.as_ref()?.split_once(':')?;
+        let (method, algo, hash) = match (method, hash.split_once(':')?) {
+            ("text", ("sha256", hash)) if !has_self_reference =>
+                return Some(make_store_path(&format!("text{}", refs), &from_base32(hash)?, &name)),
+            ("fixed", ("r", hash)) => { let (algo, hash) = hash.split_once(':')?; ("r:", algo, hash) }
+            ("fixed", (algo, hash)) => ("", algo, hash),
+            _ => return None
+        };
+        make_fixed_output_path(method, algo, &from_base32(hash)?, &refs, &name)
+    }
+
+    /// Whether Nix would accept this narinfo with the given trusted-public-keys.
+    /// Content-addressed paths don't need signatures, as long as the path matches the content
+    pub fn is_trusted(&self, keys: &[PublicKey]) -> bool {
+        if self.content_addressed_path().as_ref() == Some(&self.store_path) { return true }
+
+        let fingerprint = self.fingerprint();
+        let trusted = self.sig.iter()
+            .any(|sig| keys.iter().any(|key| key.verify(&fingerprint, sig)));
+        if !trusted { trace!("{} is not signed by a trusted key", self.store_path) }
+        trusted
+    }
+}
+
+#[test]
+fn verify_narinfo() {
+    let keys: Vec<PublicKey> = vec![CACHE_NIXOS_ORG_KEY.parse().unwrap()];
+    let others: Vec<PublicKey> = vec!["example.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".parse().unwrap()];
+
+    let mut info = NarInfo::parse(include_bytes!("../assets/dejagnu.narinfo")).unwrap();
+    assert!(info.is_trusted(&keys));
+    assert!(!info.is_trusted(&others));
+    assert!(!info.is_trusted(&[]));
+
+    info.nar_size += 1;
+    assert!(!info.is_trusted(&keys));
+
+    let info = NarInfo::parse(include_bytes!("../assets/blender.narinfo")).unwrap();
+    assert!(info.is_trusted(&keys));
+}
+
+#[test]
+fn parse_public_key() {
+    assert!("cache.nixos.org-1".parse::<PublicKey>().is_err());
+    assert!("cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGsp".parse::<PublicKey>().is_err());
+    assert_eq!(CACHE_NIXOS_ORG_KEY.parse::<PublicKey>().unwrap().name, "cache.nixos.org-1");
+}
+
+#[test]
+fn trust_content_addressed() {
+    let mut info = NarInfo::parse(include_bytes!("../assets/dejagnu.narinfo")).unwrap();
+    info.store_path = String::from("/nix/store/id71yr7x2cbrrr8pb51qf2jwjq19rkfq-xz-5.2.4.tar.bz2");
+    info.references.clear();
+    info.sig.clear();
+    assert!(!info.is_trusted(&[]));
+
+    // the output of assets/xz.tar.bz2.drv, which is fetched with a flat sha256
+    info.ca = Some(String::from("fixed:sha256:1gxpayfagb4v7xfhs2w6h7k56c6hwwav1rk48bj8hggljlmgs4rk"));
+    assert_eq!(info.content_addressed_path().as_ref(), Some(&info.store_path));
+    assert!(info.is_trusted(&[]));
+
+    // the CA field has to match the path, not just be there
+    info.ca = Some(String::from("fixed:r:sha256:1gxpayfagb4v7xfhs2w6h7k56c6hwwav1rk48bj8hggljlmgs4rk"));
+    assert!(!info.is_trusted(&[]));
+}
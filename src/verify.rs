@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use serde_derive::Serialize;
+use log::warn;
+
+use crate::{ StoreItem, StoreCache };
+
+/// An output path recorded in a .drv that isn't what Nix would compute
+#[derive(Debug, Serialize)]
+pub struct Mismatch {
+    pub drv: String,
+    pub output: String,
+    pub recorded: String,
+    pub computed: String
+}
+
+/// A .drv whose store path doesn't match its contents
+#[derive(Debug, Serialize)]
+pub struct DrvMismatch {
+    pub recorded: String,
+    pub computed: String
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct Verification {
+    /// Derivations whose output paths were recomputed
+    pub checked: u64,
+    /// Derivations whose output paths couldn't be recomputed, e.g. because of missing inputs,
+    /// or because they're only known after building, like floating content-addressed ones
+    pub skipped: u64,
+    pub mismatched: Vec<Mismatch>,
+    pub mismatched_drvs: Vec<DrvMismatch>
+}
+
+impl Verification {
+    /// Recomputes the store paths and output paths of every derivation in store
+    pub fn of(store: &StoreCache) -> Self {
+        let mut verification = Verification::default();
+        let mut known = HashMap::new();
+
+        for (hash, item) in store.entries() {
+            let drv = match item { StoreItem::Drv(drv) => drv, _ => continue };
+            let drv_path = store.path_of(*hash);
+
+            let computed = drv.compute_drv_path();
+            if computed != drv_path {
+                warn!("{} should be {}", drv_path, computed);
+                verification.mismatched_drvs.push(DrvMismatch { recorded: drv_path.clone(), computed });
+            }
+
+            let computed = match drv.compute_output_paths(store, &mut known) {
+                Ok(computed) if computed.is_empty() => { verification.skipped += 1; continue }
+                Ok(computed) => computed,
+                Err(e) => { warn!("unable to verify {}: {}", drv_path, e); verification.skipped += 1; continue }
+            };
+            verification.checked += 1;
+
+            for (key, computed) in computed {
+                let recorded = drv.find_output(&key).map(|output| &output.path[..]).unwrap_or("");
+                if recorded != computed {
+                    warn!("output {} of {} should be {}", key, drv_path, computed);
+                    verification.mismatched.push(Mismatch {
+                        drv: drv_path.clone(),
+                        output: key,
+                        recorded: recorded.to_owned(),
+                        computed
+                    });
+                }
+            }
+        }
+
+        verification.mismatched.sort_by(|a, b| (&a.drv, &a.output).cmp(&(&b.drv, &b.output)));
+        verification.mismatched_drvs.sort_by(|a, b| a.recorded.cmp(&b.recorded));
+        verification
+    }
+}
+
+#[test]
+fn verify_output_paths() {
+    use crate::{ StoreHash, derivation::test_myname };
+
+    let (drv_path, mut myname) = test_myname();
+    let out_path = "/nix/store/40s0qmrfb45vlh6610rk29ym318dswdr-myname";
+    let mut store = StoreCache::default();
+    store.discover_build_time_closure(StoreHash::from_path(drv_path).unwrap(), &myname).unwrap();
+
+    let verification = Verification::of(&store);
+    assert_eq!((verification.checked, verification.skipped), (1, 0));
+    assert!(verification.mismatched.is_empty());
+    assert!(verification.mismatched_drvs.is_empty());
+
+    // hand-edited builder, but the old paths
+    myname.builder = String::from("otherbuilder");
+    let mut store = StoreCache::default();
+    store.discover_build_time_closure(StoreHash::from_path(drv_path).unwrap(), &myname).unwrap();
+
+    let verification = Verification::of(&store);
+    assert_eq!(verification.mismatched_drvs.len(), 1);
+    assert_eq!(verification.mismatched_drvs[0].recorded, drv_path);
+    assert_ne!(verification.mismatched_drvs[0].computed, drv_path);
+    assert_eq!(verification.mismatched.len(), 1);
+    assert_eq!(verification.mismatched[0].drv, drv_path);
+    assert_eq!(verification.mismatched[0].recorded, out_path);
+    assert_ne!(verification.mismatched[0].computed, out_path);
+
+    // floating outputs have nothing to compare against
+    myname.outputs[0].path.clear();
+    let mut store = StoreCache::default();
+    store.discover_build_time_closure(StoreHash::from_path(drv_path).unwrap(), &myname).unwrap();
+    let verification = Verification::of(&store);
+    assert_eq!((verification.checked, verification.skipped), (0, 1));
+    assert!(verification.mismatched.is_empty());
+}